@@ -0,0 +1,400 @@
+//! Remote management routes, mounted at `/admin`
+
+use rocket::{get, post, delete, FromForm, Responder};
+use rocket::State;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use utoipa::IntoParams;
+
+use crate::auth::Admin;
+use crate::database::WordDb;
+use crate::language::Language;
+use crate::jobs::{Jobs, JobStatus, JobState, JobError, JobEvent};
+
+/// Start installing or upgrading a language in the background
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    params(
+        ("lang", description = "Language code"),
+    ),
+    responses(
+        (status = 202, description = "The upgrade was started", body = JobStatus),
+        (status = 401, description = "Wrong or missing admin password"),
+        (status = 404, description = "The language is not available"),
+        (status = 409, description = "The language is already being upgraded, with the running job, or being removed", body = JobStatus)
+    ),
+    security(("admin" = []))
+)]
+#[post("/langs/<lang>/upgrade")]
+pub fn upgrade_lang(_admin: Admin,
+                    db: &State<WordDb>,
+                    jobs: &State<Jobs>,
+                    lang: &str) -> Option<Result<status::Custom<Json<JobStatus>>, Status>> {
+    let lang = db.get_lang(lang)?;
+
+    Some(match jobs.start_upgrade(db, lang) {
+        Ok(job) => Ok(status::Custom(Status::Accepted, Json(job.status()))),
+        Err(JobError::AlreadyRunning(id)) => {
+            Ok(status::Custom(Status::Conflict, Json(jobs.get(id).unwrap().status())))
+        },
+        Err(JobError::Removing) => Err(Status::Conflict)
+    })
+}
+
+#[derive(Responder)]
+pub enum RemoveResponse {
+    #[response(status = 204)]
+    Removed(()),
+    #[response(status = 409)]
+    Busy(Json<JobStatus>),
+    #[response(status = 409)]
+    Removing(()),
+    #[response(status = 500)]
+    Failed(String),
+}
+
+/// Query string of language removals
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RemoveParams {
+    /// Also remove the cached dump of the language
+    cache: Option<bool>,
+}
+
+/// Uninstall a language
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    params(
+        ("lang", description = "Language code"),
+        RemoveParams,
+    ),
+    responses(
+        (status = 204, description = "The language was removed"),
+        (status = 401, description = "Wrong or missing admin password"),
+        (status = 404, description = "The language is not installed"),
+        (status = 409, description = "The language is being upgraded, with the running job, or already being removed", body = JobStatus),
+        (status = 500, description = "The database couldn't be written to")
+    ),
+    security(("admin" = []))
+)]
+#[delete("/langs/<lang>?<params..>")]
+pub async fn remove_lang(_admin: Admin,
+                         db: &State<WordDb>,
+                         jobs: &State<Jobs>,
+                         lang: &str,
+                         params: RemoveParams) -> Option<RemoveResponse> {
+    let lang = db.get_installed_lang(lang)?;
+
+    // Held until the language is gone, so no upgrade starts writing it
+    // meanwhile
+    let _removal = match jobs.start_removal(&lang) {
+        Ok(removal) => removal,
+        Err(JobError::AlreadyRunning(id)) => {
+            return Some(RemoveResponse::Busy(Json(jobs.get(id).unwrap().status())));
+        },
+        Err(JobError::Removing) => return Some(RemoveResponse::Removing(()))
+    };
+
+    let db = db.inner().clone();
+
+    // Removing files blocks
+    let result = rocket::tokio::task::spawn_blocking(move || db.remove_lang(&lang, params.cache.unwrap_or(false)))
+                                  .await
+                                  .unwrap();
+
+    Some(match result {
+        Ok(()) => RemoveResponse::Removed(()),
+        Err(e) => RemoveResponse::Failed(e.to_string())
+    })
+}
+
+/// Re-read the installed languages from the database, and the catalog of
+/// installable ones
+///
+/// Only needed for languages upgraded or catalogs refreshed outside of the
+/// daemon, and only if they should show up before the next periodic check.
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    responses(
+        (status = 200, description = "Installed languages after reloading", body = [Language]),
+        (status = 401, description = "Wrong or missing admin password")
+    ),
+    security(("admin" = []))
+)]
+#[post("/reload")]
+pub fn reload_langs(_admin: Admin, db: &State<WordDb>) -> Json<Vec<Language>> {
+    db.reload_catalog();
+    db.reload_langs();
+
+    Json(db.installed_langs().to_vec())
+}
+
+/// List running upgrade jobs and the last finished ones
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    responses(
+        (status = 200, description = "Jobs, oldest first", body = [JobStatus]),
+        (status = 401, description = "Wrong or missing admin password")
+    ),
+    security(("admin" = []))
+)]
+#[get("/jobs")]
+pub fn get_jobs(_admin: Admin, jobs: &State<Jobs>) -> Json<Vec<JobStatus>> {
+    Json(jobs.list())
+}
+
+/// Get the status of an upgrade job
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    params(
+        ("id", description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Status of the job", body = JobStatus),
+        (status = 401, description = "Wrong or missing admin password"),
+        (status = 404, description = "No such job")
+    ),
+    security(("admin" = []))
+)]
+#[get("/jobs/<id>")]
+pub fn get_job(_admin: Admin, jobs: &State<Jobs>, id: u64) -> Option<Json<JobStatus>> {
+    Some(Json(jobs.get(id)?.status()))
+}
+
+/// Follow the progress of an upgrade job as server-sent events
+///
+/// The current status is sent first as a `status` event, followed by `phase`
+/// and `progress` events as the upgrade goes. The stream ends with a
+/// `succeeded`, `failed` or `cancelled` event. Every event carries the
+/// job's status as JSON.
+#[utoipa::path(
+    tag = "admin",
+    context_path = "/admin",
+    params(
+        ("id", description = "Job id"),
+    ),
+    responses(
+        (status = 200, description = "Stream of events", content_type = "text/event-stream", body = JobStatus),
+        (status = 401, description = "Wrong or missing admin password"),
+        (status = 404, description = "No such job")
+    ),
+    security(("admin" = []))
+)]
+#[get("/jobs/<id>/events")]
+pub fn get_job_events(_admin: Admin, jobs: &State<Jobs>, id: u64) -> Option<EventStream![]> {
+    let job = jobs.get(id)?;
+
+    // Subscribe before taking the status, so no event falls in between
+    let mut events = job.subscribe();
+    let status = job.status();
+
+    Some(EventStream! {
+        yield Event::json(&status).event("status");
+
+        if status.state != JobState::Running {
+            return;
+        }
+
+        loop {
+            match events.recv().await {
+                Ok(JobEvent::Phase(status)) => yield Event::json(&status).event("phase"),
+                Ok(JobEvent::Progress(status)) => yield Event::json(&status).event("progress"),
+                Ok(JobEvent::Finished(status)) => {
+                    let event = match status.state {
+                        JobState::Failed => "failed",
+                        JobState::Cancelled => "cancelled",
+                        _ => "succeeded"
+                    };
+
+                    yield Event::json(&status).event(event);
+                    break;
+                },
+                // Only progress is lost, the next event brings it up to date
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+    use crate::database::Progress;
+    use crate::testing::{self, TempDir};
+
+    async fn client() -> (TempDir, Client) {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        (dir, client)
+    }
+
+    fn jobs(client: &Client) -> &Jobs {
+        client.rocket().state::<Jobs>().unwrap()
+    }
+
+    /// Names of the events in a stream
+    fn events(body: &str) -> Vec<&str> {
+        body.lines().filter_map(|line| line.strip_prefix("event:")).map(str::trim).collect()
+    }
+
+    #[rocket::async_test]
+    async fn job_events_follow_the_upgrade() {
+        let (_dir, client) = client().await;
+        let job = jobs(&client).add_running(&Language::new("pl", "Polish"));
+
+        let response = client.get("/admin/jobs/1/events")
+                             .header(Header::new("Authorization", testing::ADMIN))
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        job.phase("Inserting entries...");
+        job.count(1, 2);
+        job.finish(JobState::Failed, Some(String::from("No dump")));
+
+        let body = response.into_string().await.unwrap();
+
+        assert_eq!(events(&body), ["status", "phase", "progress", "failed"]);
+        assert!(body.contains(r#""error":"No dump""#));
+    }
+
+    #[rocket::async_test]
+    async fn finished_jobs_only_send_their_status() {
+        let (_dir, client) = client().await;
+        jobs(&client).add_running(&Language::new("pl", "Polish")).finish(JobState::Succeeded, None);
+
+        let body = client.get("/admin/jobs/1/events")
+                         .header(Header::new("Authorization", testing::ADMIN))
+                         .dispatch()
+                         .await
+                         .into_string()
+                         .await
+                         .unwrap();
+
+        assert_eq!(events(&body), ["status"]);
+        assert!(body.contains(r#""state":"succeeded""#));
+    }
+
+    #[rocket::async_test]
+    async fn reload_picks_up_languages_installed_elsewhere() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        testing::install(&settings, &WordDb::new(&settings, "inflectived.db"), "pol").await;
+
+        let response = client.get("/v1/langs?installed=true").dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), "[]");
+
+        let response = client.post("/admin/reload")
+                             .header(Header::new("Authorization", testing::ADMIN))
+                             .dispatch()
+                             .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let langs: Vec<serde_json::Value> = response.into_json().await.unwrap();
+        assert_eq!(langs.len(), 1);
+        assert_eq!(langs[0]["code"], "pol");
+
+        let response = client.get("/v1/langs?installed=true").dispatch().await;
+        assert!(response.into_string().await.unwrap().contains(r#""code":"pol""#));
+
+        // The catalog, refreshed by the CLI
+        crate::catalog::save(&settings, &[Language::new("grc", "Ancient Greek")]).unwrap();
+
+        client.post("/admin/reload")
+              .header(Header::new("Authorization", testing::ADMIN))
+              .dispatch()
+              .await;
+
+        let langs: Vec<serde_json::Value> = client.get("/v1/langs").dispatch().await.into_json().await.unwrap();
+        assert_eq!(langs.len(), 1);
+        assert_eq!(langs[0]["code"], "grc");
+    }
+
+    #[rocket::async_test]
+    async fn removing_languages() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        let remove = |uri: &'static str| client.delete(uri).header(Header::new("Authorization", testing::ADMIN));
+
+        assert_eq!(client.delete("/admin/langs/pol").dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(remove("/admin/langs/ger").dispatch().await.status(), Status::NotFound);
+
+        let job = jobs(&client).add_running(&lang);
+        assert_eq!(remove("/admin/langs/pol").dispatch().await.status(), Status::Conflict);
+        job.finish(JobState::Succeeded, None);
+
+        let removal = jobs(&client).start_removal(&lang).ok().unwrap();
+        assert_eq!(remove("/admin/langs/pol").dispatch().await.status(), Status::Conflict);
+        let upgrade = client.post("/admin/langs/pol/upgrade").header(Header::new("Authorization", testing::ADMIN));
+        assert_eq!(upgrade.dispatch().await.status(), Status::Conflict);
+        drop(removal);
+
+        assert_eq!(remove("/admin/langs/pol").dispatch().await.status(), Status::NoContent);
+
+        assert!(db.installed_langs().is_empty());
+        assert!(!Path::new(&db.lang_path("pol")).exists());
+        // Kept unless asked for
+        assert!(Path::new(&db.cache_file(&lang)).exists());
+
+        assert_eq!(remove("/admin/langs/pol").dispatch().await.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn removing_languages_with_their_dump() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        let response = client.delete("/admin/langs/pol?cache=true")
+                             .header(Header::new("Authorization", testing::ADMIN))
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(!Path::new(&db.cache_file(&lang)).exists());
+    }
+
+    #[rocket::async_test]
+    async fn job_events_need_a_job_and_the_password() {
+        let (_dir, client) = client().await;
+
+        let response = client.get("/admin/jobs/1/events").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client.get("/admin/jobs/1/events")
+                             .header(Header::new("Authorization", testing::ADMIN))
+                             .dispatch()
+                             .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
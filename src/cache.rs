@@ -0,0 +1,224 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::Request;
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+
+use crate::language::Language;
+
+/// Value of the Cache-Control header sent along with lookups
+pub struct CacheControl(pub String);
+
+impl CacheControl {
+    /// What's sent unless configured. Lookups needing an API key are kept
+    /// out of shared caches, which would answer them without one
+    pub fn default_for(require_api_key: bool) -> Self {
+        if require_api_key {
+            CacheControl(String::from("private, max-age=3600"))
+        } else {
+            CacheControl(String::from("public, max-age=3600"))
+        }
+    }
+}
+
+/// Validators of a language's data, which only changes when it's upgraded
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn for_lang(lang: &Language) -> Option<Self> {
+        let version = lang.version.as_ref()?;
+        let upgraded = lang.upgraded?;
+
+        Some(Self {
+            etag: format!("\"{}-{}.{}.{}-{}\"",
+                          lang.code, version.0, version.1, version.2, upgraded),
+            last_modified: UNIX_EPOCH + Duration::from_secs(upgraded as u64),
+        })
+    }
+}
+
+/// Conditional request headers sent by the client
+pub struct Preconditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<SystemTime>,
+}
+
+impl Preconditions {
+    /// Whether the client's copy is still up to date
+    pub fn fresh(&self, validators: &Validators) -> bool {
+        // If-Modified-Since is ignored when If-None-Match is present (RFC 7232)
+        if let Some(if_none_match) = &self.if_none_match {
+            return if_none_match.split(',')
+                                .map(|tag| tag.trim())
+                                .any(|tag| tag == "*"
+                                        || tag.trim_start_matches("W/") == validators.etag);
+        }
+
+        match self.if_modified_since {
+            Some(since) => validators.last_modified <= since,
+            None => false
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preconditions {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = req.headers();
+
+        request::Outcome::Success(Self {
+            if_none_match: headers.get_one("If-None-Match").map(String::from),
+            if_modified_since: headers.get_one("If-Modified-Since")
+                                      .and_then(|date| httpdate::parse_http_date(date).ok()),
+        })
+    }
+}
+
+/// A response carrying validators, or a 304 if the client's copy is fresh
+pub struct Cached<R> {
+    body: Option<R>,
+    validators: Option<Validators>,
+    cache_control: String,
+}
+
+impl<R> Cached<R> {
+    /// Respond with the body built by `make_body`, skipping it altogether
+    /// with a 304 if the preconditions are met
+    pub fn new<F>(make_body: F,
+                  validators: Option<Validators>,
+                  preconditions: &Preconditions,
+                  cache_control: &CacheControl) -> Self
+        where F: FnOnce() -> R {
+        let fresh = match &validators {
+            Some(validators) => preconditions.fresh(validators),
+            None => false
+        };
+
+        Self {
+            body: if fresh { None } else { Some(make_body()) },
+            validators,
+            cache_control: cache_control.0.clone(),
+        }
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = match self.body {
+            Some(body) => body.respond_to(req)?,
+            None => Response::build().status(Status::NotModified).finalize()
+        };
+
+        // Without validators the client would be caching data that can
+        // change under its feet, so don't tell it to
+        if let Some(validators) = self.validators {
+            response.set_header(Header::new("ETag", validators.etag));
+            response.set_header(Header::new("Last-Modified",
+                                            httpdate::fmt_http_date(validators.last_modified)));
+
+            if !self.cache_control.is_empty() {
+                response.set_header(Header::new("Cache-Control", self.cache_control));
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::Version;
+
+    #[test]
+    fn keyed_lookups_are_private() {
+        assert_eq!(CacheControl::default_for(false).0, "public, max-age=3600");
+        assert_eq!(CacheControl::default_for(true).0, "private, max-age=3600");
+    }
+
+    fn validators() -> Validators {
+        let mut lang = Language::new("pol", "Polish");
+        lang.version = Some(Version(0, 1, 0));
+        lang.upgraded = Some(1_600_000_000);
+
+        Validators::for_lang(&lang).unwrap()
+    }
+
+    fn preconditions(if_none_match: Option<&str>, if_modified_since: Option<u64>) -> Preconditions {
+        Preconditions {
+            if_none_match: if_none_match.map(String::from),
+            if_modified_since: if_modified_since.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    #[test]
+    fn validators_change_with_upgrades() {
+        let validators = validators();
+
+        assert_eq!(validators.etag, "\"pol-0.1.0-1600000000\"");
+        assert_eq!(validators.last_modified, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+    }
+
+    #[test]
+    fn no_validators_without_upgrade_time() {
+        let mut lang = Language::new("pol", "Polish");
+        lang.version = Some(Version(0, 1, 0));
+
+        assert!(Validators::for_lang(&lang).is_none());
+    }
+
+    #[test]
+    fn if_none_match() {
+        let validators = validators();
+
+        assert!(preconditions(Some("\"pol-0.1.0-1600000000\""), None).fresh(&validators));
+        assert!(preconditions(Some("W/\"pol-0.1.0-1600000000\""), None).fresh(&validators));
+        assert!(preconditions(Some("\"a\", \"pol-0.1.0-1600000000\""), None).fresh(&validators));
+        assert!(preconditions(Some("*"), None).fresh(&validators));
+
+        assert!(!preconditions(Some("\"pol-0.1.0-1500000000\""), None).fresh(&validators));
+        assert!(!preconditions(Some("pol-0.1.0-1600000000"), None).fresh(&validators));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let validators = validators();
+
+        assert!(preconditions(None, Some(1_600_000_000)).fresh(&validators));
+        assert!(preconditions(None, Some(1_700_000_000)).fresh(&validators));
+        assert!(!preconditions(None, Some(1_500_000_000)).fresh(&validators));
+        assert!(!preconditions(None, None).fresh(&validators));
+    }
+
+    #[test]
+    fn if_none_match_wins_over_if_modified_since() {
+        let validators = validators();
+
+        assert!(!preconditions(Some("\"other\""), Some(1_700_000_000)).fresh(&validators));
+    }
+
+    #[test]
+    fn fresh_requests_skip_the_body() {
+        let validators = validators();
+        let cache_control = CacheControl(String::from("max-age=60"));
+
+        let cached = Cached::new(|| "body",
+                                 Some(validators.clone()),
+                                 &preconditions(Some(&validators.etag), None),
+                                 &cache_control);
+        assert!(cached.body.is_none());
+
+        let cached = Cached::new(|| "body",
+                                 Some(validators),
+                                 &preconditions(Some("\"other\""), None),
+                                 &cache_control);
+        assert_eq!(cached.body, Some("body"));
+    }
+}
@@ -0,0 +1,286 @@
+//! The catalog of installable languages, kept in `catalog.json` in the data
+//! directory
+//!
+//! It's refreshed from an index of the dumps, either kaikki's own page
+//! listing them or a JSON list of languages, as a mirror may serve. Until
+//! then, the built-in list is used.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+use serde::{Serialize, Deserialize};
+
+use crate::iso639;
+use crate::language::Language;
+use crate::settings::Settings;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CatalogEntry {
+    code: String,
+    name: String,
+    /// Where the dump is downloaded from, `source_url` if not given
+    url: Option<String>,
+}
+
+fn path(settings: &Settings) -> String {
+    format!("{}/catalog.json", settings.data_dir)
+}
+
+/// Read the catalog, falling back to the built-in list if it wasn't
+/// refreshed yet or can't be read
+pub fn load(settings: &Settings) -> Vec<Language> {
+    let data = match fs::read_to_string(path(settings)) {
+        Ok(data) => data,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Couldn't read the language catalog: {}", e);
+            }
+
+            return Language::list_langs();
+        }
+    };
+
+    match serde_json::from_str::<Vec<CatalogEntry>>(&data) {
+        Ok(entries) => to_langs(entries),
+        Err(e) => {
+            log::warn!("Couldn't parse the language catalog: {}", e);
+            Language::list_langs()
+        }
+    }
+}
+
+fn to_langs(entries: Vec<CatalogEntry>) -> Vec<Language> {
+    let mut langs: Vec<Language> = entries.into_iter()
+                                          .filter(|entry| {
+                                              // Both end up in file names,
+                                              // and the name in dump URLs
+                                              let valid = Language::valid_code(&entry.code)
+                                                  && !entry.name.is_empty()
+                                                  && !entry.name.contains('/');
+
+                                              if !valid {
+                                                  log::warn!(lang = entry.code.as_str();
+                                                             "Skipping catalog entry with a bad code or name: {:?}",
+                                                             entry.name);
+                                              }
+
+                                              valid
+                                          })
+                                          .map(|entry| {
+                                              let mut lang = Language::new(&entry.code, &entry.name);
+                                              lang.url = entry.url;
+                                              lang
+                                          })
+                                          .collect();
+
+    langs.sort();
+
+    let mut codes = HashSet::new();
+    langs.retain(|lang| codes.insert(lang.code.clone()));
+
+    langs
+}
+
+/// Write the catalog, replacing the previous one at once
+pub fn save(settings: &Settings, langs: &[Language]) -> io::Result<()> {
+    let entries: Vec<CatalogEntry> = langs.iter()
+                                          .map(|lang| CatalogEntry {
+                                              code: lang.code.clone(),
+                                              name: lang.name.clone(),
+                                              url: lang.url.clone(),
+                                          })
+                                          .collect();
+
+    let path = path(settings);
+    let tmp_path = format!("{}.tmp", path);
+
+    fs::create_dir_all(&settings.data_dir)?;
+    fs::write(&tmp_path, serde_json::to_string_pretty(&entries).unwrap())?;
+    fs::rename(&tmp_path, &path)
+}
+
+/// Fetch and parse an index of the dumps, from a URL or a local file
+pub async fn fetch_index(settings: &Settings, index: &str) -> Result<Vec<Language>, String> {
+    let data = if index.starts_with("http://") || index.starts_with("https://") {
+        let response = reqwest::get(index).await.map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", index, response.status()));
+        }
+
+        response.text().await.map_err(|e| e.to_string())?
+    } else {
+        let path = index.strip_prefix("file://").unwrap_or(index);
+
+        fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?
+    };
+
+    let langs = if data.trim_start().starts_with('[') {
+        let entries: Vec<CatalogEntry> = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+        to_langs(entries)
+    } else {
+        to_langs(parse_html_index(settings, &data))
+    };
+
+    if langs.is_empty() {
+        return Err(format!("No languages found in {}", index));
+    }
+
+    Ok(langs)
+}
+
+/// Languages linked to from kaikki's index page, as
+/// `<a href="{dir}/index.html">{name}</a>`
+fn parse_html_index(settings: &Settings, html: &str) -> Vec<CatalogEntry> {
+    let builtin = Language::list_langs();
+    let mut entries = Vec::new();
+
+    for link in html.split("<a href=\"").skip(1) {
+        let (href, rest) = match link.split_once('"') {
+            Some(parts) => parts,
+            None => continue
+        };
+
+        let dir = match href.strip_suffix("/index.html") {
+            // Only the language directories next to the page
+            Some(dir) if !dir.is_empty() && !dir.starts_with('.') && !dir.contains(['/', ':']) => dir,
+            _ => continue
+        };
+
+        let name = match rest.split_once('>').and_then(|(_, rest)| rest.split_once("</a>")) {
+            Some((name, _)) => unescape(name.trim()),
+            None => continue
+        };
+
+        // Languages the daemon always knew keep their code, so installed
+        // ones still match
+        let code = match builtin.iter().find(|lang| lang.name == name) {
+            Some(lang) => lang.code.clone(),
+            None => code_for(&name)
+        };
+
+        if code.is_empty() {
+            continue;
+        }
+
+        entries.push(CatalogEntry {
+            code,
+            name,
+            url: Some(settings.source_url(dir)),
+        });
+    }
+
+    entries
+}
+
+/// Code of a language the index gives no code for, made up from its name
+/// if it has no standard code
+fn code_for(name: &str) -> String {
+    if let Some(code) = iso639::by_name(name).as_ref().and_then(|codes| codes.preferred()) {
+        return String::from(code);
+    }
+
+    name.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn entry(code: &str, name: &str) -> CatalogEntry {
+        CatalogEntry { code: String::from(code), name: String::from(name), url: None }
+    }
+
+    #[test]
+    fn index_pages_are_parsed() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let html = r#"
+        <a href="https://kaikki.org/">Home</a>
+        <ul>
+          <li><a href="Polish/index.html">Polish</a> (1234 senses)</li>
+          <li><a href="Czech/index.html">Czech</a></li>
+          <li><a href="Low%20Dutch/index.html" class="lang">Low Dutch &amp; Co.</a></li>
+          <li><a href="../index.html">Up</a></li>
+          <li><a href="Polish/words/index.html">Words</a></li>
+          <li><a href="Broken/index.html">Unclosed</li>
+        </ul>"#;
+
+        let entries = parse_html_index(&settings, html);
+        let found: Vec<(&str, &str)> = entries.iter()
+                                              .map(|entry| (entry.code.as_str(), entry.name.as_str()))
+                                              .collect();
+
+        // Built-in languages keep their code, others get their standard
+        // one, or one made up from their name
+        assert_eq!(found, [("pol", "Polish"), ("cze", "Czech"), ("lowdutchco", "Low Dutch & Co.")]);
+        assert_eq!(entries[0].url.as_deref(), Some(settings.source_url("Polish").as_str()));
+    }
+
+    #[test]
+    fn bad_entries_are_dropped() {
+        let langs = to_langs(vec![entry("pol", "Polish"),
+                                  entry("../pol", "Polish"),
+                                  entry("", "Nameless"),
+                                  entry("x y", "Spaced"),
+                                  entry("evil", "../../etc/passwd"),
+                                  entry("none", ""),
+                                  entry("old-pl_1", "Old Polish")]);
+
+        let codes: Vec<&str> = langs.iter().map(|lang| lang.code.as_str()).collect();
+
+        assert_eq!(codes, ["old-pl_1", "pol"]);
+    }
+
+    #[test]
+    fn languages_are_sorted_and_unique() {
+        let langs = to_langs(vec![entry("pol", "Polish"), entry("cze", "Czech"), entry("pol", "Polish again")]);
+
+        let names: Vec<&str> = langs.iter().map(|lang| lang.name.as_str()).collect();
+
+        assert_eq!(names, ["Czech", "Polish"]);
+    }
+
+    #[test]
+    fn catalogs_round_trip() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let mut langs = to_langs(vec![entry("pol", "Polish")]);
+        langs[0].url = Some(String::from("https://mirror.example/pol.json"));
+
+        save(&settings, &langs).unwrap();
+        let loaded = load(&settings);
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].url, langs[0].url);
+        // Mirrors are the server's business
+        assert!(serde_json::to_value(&loaded[0]).unwrap().get("url").is_none());
+    }
+
+    #[tokio::test]
+    async fn json_indexes_are_validated() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let path = format!("{}/index.json", dir.0);
+
+        fs::write(&path, r#"[{"code": "pol", "name": "Polish"}, {"code": "..", "name": "Up"}]"#).unwrap();
+        let langs = fetch_index(&settings, &path).await.unwrap();
+        assert_eq!(langs.len(), 1);
+
+        fs::write(&path, r#"[{"code": "/", "name": "Root"}]"#).unwrap();
+        assert!(fetch_index(&settings, &path).await.is_err());
+    }
+}
@@ -1,51 +1,339 @@
 use std::fs;
 use std::fs::File;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, RwLock};
 
-use reqwest;
 use rusqlite::{Connection, Transaction, ErrorCode};
 use rusqlite::Error::SqliteFailure;
 use rusqlite::params;
 use serde_json::Value;
 use serde_json::json;
-use serde_json;
 
 use crate::language::Language;
 use crate::entry::{WiktionaryEntries, WiktionaryEntry};
 use crate::entry::Form;
-use crate::{DB_DIR, CACHE_DIR, MAJOR, MINOR, PATCH};
+use crate::version::Version;
+use crate::settings::Settings;
 use crate::util;
+use crate::keys::ApiKeyInfo;
+use crate::pool::{self, Pool, PooledConnection};
+use crate::migrations;
+use crate::catalog;
 
+#[derive(Debug)]
 pub enum DbError {
     AccessDenied,
+    /// Another connection held the database locked for longer than
+    /// `pool::BUSY_TIMEOUT`, which happens while a language is upgraded
+    Busy,
+    /// The upgrade was cancelled and nothing was written
+    Cancelled,
+    Sqlite(rusqlite::Error),
+    /// Reading or writing a file next to the databases failed, e.g. a cached
+    /// dump
+    Io(io::Error),
+    /// The language's dump couldn't be downloaded
+    Download(String),
+    /// A restore copied fewer or more entries than expected, and was rolled
+    /// back
+    Incomplete { restored: usize, expected: usize },
 }
 
 impl fmt::Display for DbError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DbError::AccessDenied => write!(f, "Access denied"),
+            DbError::Busy => write!(f, "The database is locked, an upgrade is probably in progress. Try again once it's done"),
+            DbError::Cancelled => write!(f, "Cancelled"),
+            DbError::Sqlite(e) => write!(f, "Database error: {}", e),
+            DbError::Io(e) => write!(f, "I/O error: {}", e),
+            DbError::Download(e) => write!(f, "Downloading the dump failed: {}", e),
+            DbError::Incomplete { restored, expected } => write!(f, "Restored {} entries of {}", restored, expected),
         }
     }
 }
 
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            SqliteFailure(f, _) if f.code == ErrorCode::ReadOnly => DbError::AccessDenied,
+            SqliteFailure(f, _) if f.code == ErrorCode::DatabaseBusy
+                                || f.code == ErrorCode::DatabaseLocked => DbError::Busy,
+            e => DbError::Sqlite(e)
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => DbError::AccessDenied,
+            _ => DbError::Io(e)
+        }
+    }
+}
+
+/// Receives progress reports from `WordDb::upgrade_lang`
+pub trait Progress: Send + Sync {
+    fn phase(&self, phase: &str);
+
+    /// Report that `done` out of `total` entries were processed in the
+    /// current phase
+    fn count(&self, _done: usize, _total: usize) {}
+
+    /// Whether the upgrade should stop, checked between phases and while
+    /// counting
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Ignores progress, for the CLI, which follows upgrades through the logs
+/// of their phases
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn phase(&self, _phase: &str) {}
+}
+
+/// Passes progress on while logging each phase as it starts and how long it
+/// took. A phase still running when dropped is logged as failed, or
+/// cancelled, as the upgrade returned early
+struct PhaseLog<'a> {
+    lang: &'a str,
+    progress: &'a dyn Progress,
+    started: Instant,
+    current: Mutex<Option<(String, Instant)>>,
+}
+
+impl<'a> PhaseLog<'a> {
+    fn new(lang: &'a str, progress: &'a dyn Progress) -> Self {
+        Self {
+            lang,
+            progress,
+            started: Instant::now(),
+            current: Mutex::new(None),
+        }
+    }
+
+    fn finish_phase(&self) {
+        if let Some((phase, started)) = self.current.lock().unwrap().take() {
+            log::info!(lang = self.lang,
+                       phase = phase.as_str(),
+                       seconds = started.elapsed().as_secs_f64();
+                       "Upgrade phase finished");
+        }
+    }
+
+    fn finish(&self) {
+        self.finish_phase();
+
+        log::info!(lang = self.lang,
+                   seconds = self.started.elapsed().as_secs_f64();
+                   "Upgrade finished");
+    }
+}
+
+impl Drop for PhaseLog<'_> {
+    fn drop(&mut self) {
+        if let Some((phase, started)) = self.current.lock().unwrap().take() {
+            let seconds = started.elapsed().as_secs_f64();
+
+            if self.progress.cancelled() {
+                log::warn!(lang = self.lang, phase = phase.as_str(), seconds; "Upgrade phase cancelled");
+            } else {
+                log::error!(lang = self.lang, phase = phase.as_str(), seconds; "Upgrade phase failed");
+            }
+        }
+    }
+}
+
+impl Progress for PhaseLog<'_> {
+    fn phase(&self, phase: &str) {
+        self.finish_phase();
+        self.progress.phase(phase);
+
+        log::info!(lang = self.lang, phase; "Upgrade phase started");
+
+        *self.current.lock().unwrap() = Some((String::from(phase), Instant::now()));
+    }
+
+    fn count(&self, done: usize, total: usize) {
+        self.progress.count(done, total);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.progress.cancelled()
+    }
+}
+
+// How often progress is counted, in entries
+const COUNT_INTERVAL: usize = 1000;
+
 /// A database of Wiktionary entries
+///
+/// Each language is kept in a database file of its own, under `langs/` in
+/// the data directory, so languages can be upgraded, backed up or removed
+/// without touching the others. A catalog database keeps track of the
+/// installed languages, along with the admin password and API keys.
+///
+/// Clones share the registry of installed languages, so reloading it through
+/// any of them is seen by all. They also share pools of read-only
+/// connections, which lookups go through.
+#[derive(Clone)]
 pub struct WordDb {
     db_path: String,
-    pub installed_langs: Vec<Language>,
-    pub installable_langs: Vec<Language>
+    settings: Settings,
+    readers: Arc<Pool>,
+    lang_readers: Arc<Mutex<HashMap<String, Arc<Pool>>>>,
+    installed_langs: Arc<RwLock<Arc<Vec<Language>>>>,
+    installable_langs: Arc<RwLock<Arc<Vec<Language>>>>,
 }
 
 impl WordDb {
-    pub fn new(db_name: &str) -> Self {
-        let db_path = format!("{}/{}", DB_DIR, db_name);
+    pub fn new(settings: &Settings, db_name: &str) -> Self {
+        let db_path = format!("{}/{}", settings.data_dir, db_name);
+
+        // Users' data directories may not exist yet. If this fails, opening
+        // the database fails right after anyway
+        fs::create_dir_all(&settings.data_dir).ok();
 
         let conn = Connection::open(&db_path).unwrap();
 
+        // WAL lets lookups go on while an upgrade writes. The mode is stored in
+        // the database, so this fails harmlessly when it can't be written to
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).ok();
+
+        let installed_langs = Self::read_installed_langs(&conn);
+
+        Self {
+            readers: Pool::new(&db_path),
+            lang_readers: Arc::new(Mutex::new(HashMap::new())),
+            db_path,
+            settings: settings.clone(),
+            installed_langs: Arc::new(RwLock::new(Arc::new(installed_langs))),
+            installable_langs: Arc::new(RwLock::new(Arc::new(catalog::load(settings)))),
+        }
+    }
+
+    /// Bring the catalog in line with the language databases, before
+    /// commands that write to them
+    ///
+    /// Moves languages out of catalogs from before they had files of their
+    /// own, and registers installs the catalog missed.
+    pub fn recover(&self) {
+        if let Err(e) = self.split_catalog() {
+            log::warn!("Couldn't move languages into files of their own: {}", e);
+        }
+
+        if let Err(e) = self.reconcile_langs() {
+            log::warn!("Couldn't check the catalog against the language databases: {}", e);
+        }
+    }
+
+    /// Move languages out of the catalog into files of their own
+    ///
+    /// Databases created before languages had their own files keep them in
+    /// the catalog, as `<code>_words` and `<code>_types` tables.
+    fn split_catalog(&self) -> rusqlite::Result<()> {
+        let catalog = self.connect()?;
+
+        let mut statement = catalog.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE '%\\_words' ESCAPE '\\'"
+        )?;
+        let codes = statement.query_map([], |row| row.get::<_, String>(0))?
+                             .collect::<rusqlite::Result<Vec<String>>>()?
+                             .iter()
+                             .map(|table| String::from(table.trim_end_matches("_words")))
+                             .collect::<Vec<String>>();
+
+        if codes.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.langs_dir()).map_err(|e| {
+            rusqlite::Error::InvalidPath(PathBuf::from(format!("{}: {}", self.langs_dir(), e)))
+        })?;
+
+        for code in codes {
+            log::info!(lang = code.as_str(); "Moving language into its own file");
+
+            // Left over by an interrupted migration, which copies into it
+            // before dropping the tables from the catalog
+            self.remove_lang_file(&code).ok();
+
+            let mut conn = self.connect_lang(&code)?;
+            conn.execute("ATTACH DATABASE ? AS catalog", [&self.db_path])?;
+
+            let transaction = conn.transaction()?;
+            Self::create_lang_tables(&transaction)?;
+            transaction.execute(&format!("INSERT INTO types SELECT * FROM catalog.{}_types", code), [])?;
+            transaction.execute(&format!("INSERT INTO words SELECT * FROM catalog.{}_words", code), [])?;
+            transaction.commit()?;
+
+            conn.execute("DETACH DATABASE catalog", [])?;
+
+            catalog.execute_batch(&format!("
+            BEGIN;
+            DROP TABLE {0}_words;
+            DROP TABLE {0}_types;
+            COMMIT;", code))?;
+        }
+
+        // The dropped tables leave their pages free rather than shrinking it
+        catalog.execute("VACUUM", [])?;
+
+        Ok(())
+    }
+
+    /// Register languages the catalog doesn't list as their databases say
+    /// they were installed, e.g. when the daemon was killed between
+    /// committing an upgrade and updating the catalog
+    fn reconcile_langs(&self) -> Result<(), DbError> {
+        let registered = Self::read_installed_langs(&self.connect()?);
+        let mut changed = false;
+
+        for code in self.lang_files() {
+            let conn = self.connect_lang(&code)?;
+
+            // Databases installed before this was recorded have no marker
+            let marker = conn.query_row("SELECT name, major, minor, patch, upgraded FROM lang", [], |row| {
+                Ok((row.get::<_, String>(0)?, Version(row.get(1)?, row.get(2)?, row.get(3)?), row.get::<_, i64>(4)?))
+            });
+
+            let (name, version, upgraded) = match marker {
+                Ok(marker) => marker,
+                Err(_) => continue
+            };
+
+            let up_to_date = registered.iter().any(|lang| {
+                lang.code == code && lang.version.as_ref() == Some(&version) && lang.upgraded == Some(upgraded)
+            });
+
+            if !up_to_date {
+                log::warn!(lang = code.as_str(); "Registering an install the catalog missed");
+
+                self.insert_version(&Language::new(&code, &name), &version, upgraded)?;
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.reload_langs();
+        }
+
+        Ok(())
+    }
+
+    fn read_installed_langs(conn: &Connection) -> Vec<Language> {
         let mut installed_langs: Vec<Language> = Vec::new();
 
         let statement = conn.prepare(
-            "SELECT code, name, major, minor, patch
+            "SELECT *
             FROM langs"
         );
 
@@ -53,148 +341,624 @@ impl WordDb {
             let mut rows = statement.query([]).unwrap();
 
             while let Some(row) = rows.next().unwrap() {
-                installed_langs.push(Language::from_row(&row));
+                installed_langs.push(Language::from_row(row));
             }
 
             installed_langs.sort();
         }
 
-        Self {
-            db_path,
-            installed_langs,
-            installable_langs: Language::list_langs(),
+        installed_langs
+    }
+
+    /// Snapshot of the installed languages
+    ///
+    /// The snapshot stays the same even if the languages are reloaded in the
+    /// meantime, so it should be taken once per request.
+    pub fn installed_langs(&self) -> Arc<Vec<Language>> {
+        self.installed_langs.read().unwrap().clone()
+    }
+
+    /// Re-read the installed languages from the database, e.g. after another
+    /// process upgraded one. Returns whether they changed.
+    pub fn reload_langs(&self) -> bool {
+        let installed_langs = Self::read_installed_langs(&self.read());
+
+        let mut current = self.installed_langs.write().unwrap();
+
+        // Languages compare by name only, which doesn't catch upgrades
+        let unchanged = current.len() == installed_langs.len()
+            && current.iter().zip(&installed_langs).all(|(a, b)|
+                a.code == b.code && a.version == b.version && a.upgraded == b.upgraded
+            );
+
+        if unchanged {
+            return false;
         }
+
+        *current = Arc::new(installed_langs);
+
+        // Upgraded or removed languages may have been replaced by new files,
+        // which pooled connections would miss
+        self.lang_readers.lock().unwrap().clear();
+
+        true
     }
 
-    pub fn connect(&self) -> Connection {
-        Connection::open(&self.db_path).unwrap()
+    /// Snapshot of the languages that can be installed, see `installed_langs`
+    pub fn installable_langs(&self) -> Arc<Vec<Language>> {
+        self.installable_langs.read().unwrap().clone()
+    }
+
+    /// Re-read the catalog of installable languages, e.g. after it was
+    /// refreshed
+    pub fn reload_catalog(&self) {
+        *self.installable_langs.write().unwrap() = Arc::new(catalog::load(&self.settings));
+    }
+
+    /// Open a connection for writing
+    pub fn connect(&self) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(&self.db_path)?;
+        conn.busy_timeout(pool::BUSY_TIMEOUT)?;
+
+        Ok(conn)
+    }
+
+    pub fn catalog_path(&self) -> &str {
+        &self.db_path
+    }
+
+    fn langs_dir(&self) -> String {
+        format!("{}/langs", self.settings.data_dir)
+    }
+
+    pub fn lang_path(&self, code: &str) -> String {
+        format!("{}/{}.db", self.langs_dir(), code)
+    }
+
+    /// Open a connection for writing to a language's database, creating it
+    /// if needed
+    pub fn connect_lang(&self, code: &str) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(self.lang_path(code))?;
+        conn.busy_timeout(pool::BUSY_TIMEOUT)?;
+
+        // Needs write access, see `new`
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).ok();
+
+        Ok(conn)
+    }
+
+    fn remove_lang_file(&self, code: &str) -> io::Result<()> {
+        let path = self.lang_path(code);
+
+        for path in [format!("{}-wal", path), format!("{}-shm", path), path] {
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Codes of the languages that have a database file, installed or not
+    pub fn lang_files(&self) -> Vec<String> {
+        let mut codes: Vec<String> = match fs::read_dir(self.langs_dir()) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok())
+                                  .filter_map(|entry| {
+                                      let name = entry.file_name().into_string().ok()?;
+                                      name.strip_suffix(".db").map(String::from)
+                                  })
+                                  .collect(),
+            Err(_) => Vec::new()
+        };
+
+        codes.sort();
+
+        codes
+    }
+
+    /// Size of the catalog and language databases in bytes, including their
+    /// write-ahead logs
+    pub fn size(&self) -> u64 {
+        let mut paths = vec![self.db_path.clone()];
+
+        for code in self.lang_files() {
+            paths.push(self.lang_path(&code));
+        }
+
+        paths.iter()
+             .flat_map(|path| [path.clone(), format!("{}-wal", path)])
+             .filter_map(|path| fs::metadata(path).ok())
+             .map(|metadata| metadata.len())
+             .sum()
+    }
+
+    /// Take a read-only connection to the catalog from the pool
+    pub fn read(&self) -> PooledConnection {
+        self.readers.get()
+    }
+
+    /// Pool of connections to a language's database, which must have a file
+    fn lang_pool(&self, code: &str) -> Arc<Pool> {
+        self.lang_readers.lock()
+                         .unwrap()
+                         .entry(String::from(code))
+                         .or_insert_with(|| Pool::new(&self.lang_path(code)))
+                         .clone()
+    }
+
+    /// Take a read-only connection to a language's database from its pool
+    ///
+    /// Takes a language rather than a code, so codes from requests are
+    /// resolved to an installed language before they get near a path. Fails
+    /// if its file is gone, e.g. removed by hand.
+    pub fn read_lang(&self, lang: &Language) -> Result<PooledConnection, DbError> {
+        Ok(self.lang_pool(&lang.code).try_get()?)
+    }
+
+    /// Whether the catalog can be read, for health checks
+    pub fn check_catalog(&self) -> rusqlite::Result<()> {
+        let conn = self.readers.try_get()?;
+
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+    }
+
+    /// Whether each table of a language's database has any rows, for health
+    /// checks
+    ///
+    /// Unlike other queries, failures are returned rather than panicking.
+    pub fn table_stats(&self, code: &str) -> rusqlite::Result<HashMap<String, bool>> {
+        let conn = self.lang_pool(code).try_get()?;
+
+        let mut statement = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+        let tables = statement.query_map([], |row| row.get::<_, String>(0))?
+                              .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        let mut stats = HashMap::new();
+
+        for table in tables {
+            let has_rows = conn.query_row(&format!("SELECT EXISTS (SELECT 1 FROM \"{}\")", table),
+                                          [],
+                                          |row| row.get(0))?;
+
+            stats.insert(table, has_rows);
+        }
+
+        Ok(stats)
     }
 
     pub fn list_available(&self) -> String {
         let mut list = String::new();
 
-        for lang in &self.installable_langs {
-            list.push_str(&format!(" - {} ({})\n", &lang.name, &lang.code));
+        for lang in self.installable_langs().iter() {
+            list.push_str(&format!(" - {} ({})\n", lang.name, lang.code));
         }
 
         list
     }
 
+    pub fn list_installed(&self) -> String {
+        let mut list = String::new();
+
+        for lang in self.installed_langs().iter() {
+            list.push_str(&format!(" - {} ({})\n", lang.name, lang.code));
+        }
+
+        list
+    }
+
+    /// Installed languages built by older versions of the daemon
+    pub fn outdated_langs(&self) -> Vec<Language> {
+        self.installed_langs().iter().filter(|lang| lang.outdated).cloned().collect()
+    }
+
+    pub fn list_outdated(&self) -> String {
+        let mut list = String::new();
+
+        for lang in self.outdated_langs() {
+            list.push_str(&format!(" - {} ({}), built by {}\n",
+                                   lang.name, lang.code, lang.version.unwrap()));
+        }
+
+        list
+    }
+
+    /// Find a language by its code or any of its standard ones
+    fn find_lang(langs: &[Language], code: &str) -> Option<Language> {
+        // Codes made up for languages without standard ones could clash
+        // with the standard codes of others
+        langs.iter()
+             .find(|lang| lang.code == code)
+             .or_else(|| langs.iter().find(|lang| lang.goes_by(code)))
+             .cloned()
+    }
+
     pub fn get_lang(&self, code: &str) -> Option<Language> {
-        for lang in &self.installable_langs {
-            if lang.code == code {
-                return Some(lang.clone())
+        Self::find_lang(&self.installable_langs(), code)
+    }
+
+    /// Get the unparsed JSON of all entries of a word
+    pub fn get_entries(&self, lang: &Language, word: &str) -> Result<Vec<String>, DbError> {
+        let conn = self.read_lang(lang)?;
+
+        let mut statement = conn.prepare_cached(
+            "SELECT content
+            FROM words
+            WHERE word = ?"
+        )?;
+
+        let mut rows = statement.query([word])?;
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(row.get(0)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Get words containing `like`, shortest first
+    pub fn get_words_like(&self,
+                          lang: &Language,
+                          like: &str,
+                          limit: usize,
+                          offset: usize) -> Result<Vec<String>, DbError> {
+        let conn = self.read_lang(lang)?;
+
+        let mut statement = conn.prepare_cached(
+            "SELECT word
+            FROM words
+            WHERE word LIKE ?
+            ORDER BY length(word) ASC
+            LIMIT ?
+            OFFSET ?"
+        )?;
+
+        let mut rows = statement.query(params![format!("%{}%", like), limit, offset])?;
+
+        let mut words = Vec::new();
+        while let Some(row) = rows.next()? {
+            words.push(row.get(0)?);
+        }
+
+        Ok(words)
+    }
+
+    /// Write all entries of a language as NDJSON, returning how many were
+    /// written
+    ///
+    /// Takes a connection to the language's database rather than `&self` so
+    /// it can be moved into a blocking task.
+    ///
+    /// Database errors are returned as I/O errors, so the caller can tell
+    /// an export that failed partway through from a complete one.
+    pub fn export_entries<W: Write>(conn: &Connection,
+                                    generated: bool,
+                                    mut writer: W) -> io::Result<usize> {
+        let to_io = io::Error::other;
+
+        let mut statement = conn.prepare(
+            "SELECT content
+            FROM words
+            ORDER BY id"
+        ).map_err(to_io)?;
+
+        let mut rows = statement.query([]).map_err(to_io)?;
+        let mut count = 0;
+
+        while let Some(row) = rows.next().map_err(to_io)? {
+            let content: String = row.get(0).map_err(to_io)?;
+
+            if !generated && WiktionaryEntry::is_generated(&content) {
+                continue;
             }
+
+            writer.write_all(content.as_bytes())?;
+            writer.write_all(b"\n")?;
+            count += 1;
         }
 
-        None
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    pub fn get_installed_lang(&self, code: &str) -> Option<Language> {
+        Self::find_lang(&self.installed_langs(), code)
     }
 
-    pub fn clean_tables(&mut self, lang: &Language) -> Result<(), DbError> {
-        let mut conn = self.connect();
-        let transaction = conn.transaction().unwrap();
+    pub fn get_admin_password_hash(&self) -> Option<String> {
+        let conn = self.read();
 
-        if let Err(e) = transaction.execute("
-        CREATE TABLE IF NOT EXISTS langs (
+        conn.query_row(
+            "SELECT password_hash FROM admin WHERE id = 1",
+            [],
+            |row| row.get(0)
+        ).ok()
+    }
+
+    /// Set or replace the admin password hash
+    pub fn set_admin_password_hash(&self, hash: &str) -> Result<(), DbError> {
+        let conn = self.connect()?;
+
+        // There's a single admin, so the table has at most one row
+        conn.execute("
+        CREATE TABLE IF NOT EXISTS admin (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            password_hash TEXT NOT NULL,
+            changed INTEGER NOT NULL
+        )", [])?;
+
+        let changed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        conn.execute("
+        INSERT OR REPLACE INTO admin (id, password_hash, changed)
+        VALUES (1, ?, ?)
+        ", params![hash, changed])?;
+
+        Ok(())
+    }
+
+    /// Add an API key, returning whether it was added. Names are kept
+    /// unique, so keys aren't replaced by accident
+    pub fn add_api_key(&self,
+                       name: &str,
+                       key_hash: &str,
+                       rate: f64,
+                       burst: u32,
+                       daily_quota: Option<u64>) -> Result<bool, DbError> {
+        let conn = self.connect()?;
+
+        conn.execute("
+        CREATE TABLE IF NOT EXISTS api_keys (
             id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-            code TINYTEXT UNIQUE NOT NULL,
+            name TINYTEXT UNIQUE NOT NULL,
+            key_hash TINYTEXT UNIQUE NOT NULL,
+            rate REAL NOT NULL,
+            burst INTEGER NOT NULL,
+            daily_quota INTEGER,
+            created INTEGER NOT NULL
+        )", [])?;
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        match conn.execute("
+        INSERT INTO api_keys (name, key_hash, rate, burst, daily_quota, created)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ", params![name, key_hash, rate, burst, daily_quota.map(|quota| quota as i64), created]) {
+            Ok(_) => Ok(true),
+            Err(SqliteFailure(f, _)) if f.code == ErrorCode::ConstraintViolation => Ok(false),
+            Err(e) => Err(DbError::from(e))
+        }
+    }
+
+    pub fn list_api_keys(&self) -> Vec<ApiKeyInfo> {
+        let conn = self.read();
+
+        let mut keys = Vec::new();
+
+        // No keys were ever added if the table doesn't exist
+        if let Ok(mut statement) = conn.prepare("SELECT * FROM api_keys ORDER BY name") {
+            let mut rows = statement.query([]).unwrap();
+
+            while let Some(row) = rows.next().unwrap() {
+                keys.push(ApiKeyInfo::from_row(row));
+            }
+        }
+
+        keys
+    }
+
+    pub fn find_api_key(&self, key_hash: &str) -> Option<ApiKeyInfo> {
+        let conn = self.read();
+
+        // Checked on every request when keys are required
+        let mut statement = conn.prepare_cached(
+            "SELECT * FROM api_keys WHERE key_hash = ?"
+        ).ok()?;
+
+        statement.query_row([key_hash], |row| Ok(ApiKeyInfo::from_row(row))).ok()
+    }
+
+    /// Remove an API key, returning whether it existed
+    pub fn remove_api_key(&self, name: &str) -> Result<bool, DbError> {
+        // Also covers the table not existing yet
+        if !self.list_api_keys().iter().any(|key| key.name == name) {
+            return Ok(false);
+        }
+
+        let conn = self.connect()?;
+
+        conn.execute("DELETE FROM api_keys WHERE name = ?", [name])?;
+
+        Ok(true)
+    }
+
+    /// Copy a language's database into a new file, as it was when the copy
+    /// started
+    pub fn snapshot_lang(&self, lang: &Language, path: &str) -> Result<(), DbError> {
+        let conn = self.read_lang(lang)?;
+
+        conn.execute("VACUUM INTO ?", [path])?;
+
+        Ok(())
+    }
+
+    /// Install a language from a copy of its database, e.g. one unpacked from
+    /// a snapshot, replacing its tables in a single transaction like upgrades
+    /// do. Rolls back unless exactly `entries` entries were copied
+    pub fn restore_lang(&self,
+                        lang: &Language,
+                        path: &str,
+                        version: &Version,
+                        upgraded: i64,
+                        entries: usize) -> Result<(), DbError> {
+        util::try_create_dir(&self.langs_dir())?;
+
+        let mut conn = self.connect_lang(&lang.code)?;
+        conn.execute("ATTACH DATABASE ? AS snapshot", [path])?;
+
+        let transaction = conn.transaction()?;
+
+        self.clean_tables(&transaction)?;
+
+        transaction.execute_batch("
+        INSERT INTO types (id, name)
+        SELECT id, name FROM snapshot.types;
+
+        INSERT INTO words (id, word, type_id, content)
+        SELECT id, word, type_id, content FROM snapshot.words;
+        ")?;
+
+        // Checked before committing, so a bad copy leaves the installed
+        // language as it was
+        let restored: i64 = transaction.query_row("SELECT count(*) FROM words", [], |row| row.get(0))?;
+
+        if restored as usize != entries {
+            return Err(DbError::Incomplete { restored: restored as usize, expected: entries });
+        }
+
+        Self::mark_installed(&transaction, lang, version, upgraded)?;
+
+        transaction.commit()?;
+
+        conn.execute("DETACH DATABASE snapshot", [])?;
+
+        self.insert_version(lang, version, upgraded)?;
+        self.reload_langs();
+
+        Ok(())
+    }
+
+    /// Record what was installed into a language's database, within the
+    /// transaction installing it, so `reconcile_langs` can tell whether the
+    /// catalog caught up
+    fn mark_installed(transaction: &Transaction,
+                      lang: &Language,
+                      version: &Version,
+                      upgraded: i64) -> rusqlite::Result<()> {
+        transaction.execute_batch("
+        CREATE TABLE IF NOT EXISTS lang (
             name TINYTEXT NOT NULL,
             major INTEGER NOT NULL,
             minor INTEGER NOT NULL,
-            patch INTEGER NOT NULL
-        )", []) {
-            match e {
-                SqliteFailure(f, _) => match f.code {
-                    ErrorCode::ReadOnly => {
-                        return Err(DbError::AccessDenied)
-                    },
-                    _ => panic!("{}", e)
-                },
-                _ => panic!("{}", e)
-            }
-        }
+            patch INTEGER NOT NULL,
+            upgraded INTEGER NOT NULL
+        );
 
-        if let Err(e) = transaction.execute(
-            "DELETE FROM langs WHERE code = ?", [&lang.code]) {
-            match e {
-                SqliteFailure(f, _) => match f.code {
-                    ErrorCode::ReadOnly => {
-                        return Err(DbError::AccessDenied)
-                    },
-                    _ => panic!("{}", e)
-                },
-                _ => panic!("{}", e)
-            }
-        };
+        DELETE FROM lang;
+        ")?;
+
+        transaction.execute("
+        INSERT INTO lang (name, major, minor, patch, upgraded)
+        VALUES (?, ?, ?, ?, ?)
+        ", params![&lang.name, version.0, version.1, version.2, upgraded])?;
 
-        transaction.execute(&format!("DROP TABLE IF EXISTS {0}_words", &lang.code), []).unwrap();
-        transaction.execute(&format!("DROP TABLE IF EXISTS {0}_types", &lang.code), []).unwrap();
+        Ok(())
+    }
 
-        transaction.execute(&format!("
-        CREATE TABLE {0}_types (
+    fn create_lang_tables(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute_batch("
+        CREATE TABLE types (
             id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
             name TINYTEXT UNIQUE NOT NULL
-        )", &lang.code), []).unwrap();
+        );
 
-        transaction.execute(&format!("
-        CREATE TABLE {0}_words (
+        CREATE TABLE words (
             id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
             word TINYTEXT NOT NULL,
             type_id INTEGER NOT NULL,
             content MEDIUMTEXT NOT NULL,
             FOREIGN KEY (type_id)
-                REFERENCES {0}_types (id)
-        )", &lang.code), []).unwrap();
+                REFERENCES types (id)
+        );
+
+        CREATE INDEX word_index
+        ON words (word);
+        ")?;
+
+        conn.execute_batch(&format!("PRAGMA user_version = {}", migrations::MIGRATIONS.len()))
+    }
 
-        transaction.execute(&format!("
-        CREATE INDEX {0}_word_index
-        ON {0}_words (word)
-        ", &lang.code), []).unwrap();
+    /// Apply the migrations a language's database is missing, in a single
+    /// transaction, returning their descriptions
+    pub fn migrate_lang(&self, lang: &Language) -> Result<Vec<&'static str>, DbError> {
+        let built = lang.version.clone().unwrap_or(Version(0, 0, 0));
 
-        transaction.commit().unwrap();
+        let mut conn = self.connect_lang(&lang.code)?;
+
+        Ok(migrations::apply(&mut conn, &built, migrations::MIGRATIONS)?)
+    }
+
+    /// Empty a language's database, within the upgrade's transaction
+    pub fn clean_tables(&self, transaction: &Transaction) -> Result<(), DbError> {
+        transaction.execute("DROP TABLE IF EXISTS main.words", [])?;
+        transaction.execute("DROP TABLE IF EXISTS main.types", [])?;
+
+        Self::create_lang_tables(transaction)?;
 
         Ok(())
     }
 
-    pub fn insert_entry(&self, transaction: &Transaction, lang: &Language, entry: &WiktionaryEntry) {
-        transaction.execute(&format!("
-        INSERT INTO {0}_words ( word, content, type_id )
+    pub fn insert_entry(&self, transaction: &Transaction, entry: &WiktionaryEntry) -> rusqlite::Result<()> {
+        transaction.execute("
+        INSERT INTO words ( word, content, type_id )
         VALUES (
                 ?, ?,
-                (SELECT id FROM {0}_types WHERE name = ?)
-        )", &lang.code),
+                (SELECT id FROM types WHERE name = ?)
+        )",
             params![entry.word,
                     entry.unparsed_json,
                     entry.type_]
-        ).unwrap();
+        )?;
+
+        Ok(())
     }
 
-    pub fn insert_entries(&mut self, lang: &Language, entries: &WiktionaryEntries) {
-        let mut conn = self.connect();
-        let transaction = conn.transaction().unwrap();
+    pub fn insert_entries(&self,
+                          transaction: &Transaction,
+                          entries: &WiktionaryEntries,
+                          progress: &dyn Progress) -> Result<(), DbError> {
+        for (i, entry) in entries.iter().enumerate() {
+            self.insert_entry(transaction, entry)?;
 
-        for entry in entries.iter() {
-            self.insert_entry(&transaction, lang, entry);
+            if i % COUNT_INTERVAL == 0 {
+                if progress.cancelled() {
+                    return Err(DbError::Cancelled);
+                }
+
+                progress.count(i, entries.len());
+            }
         }
+        progress.count(entries.len(), entries.len());
 
-        transaction.commit().unwrap();
+        Ok(())
     }
 
     /// Generate missing "form-of" entries
-    pub fn generate_entries(&mut self, lang: &Language, entries: &WiktionaryEntries) {
-        let mut conn = self.connect();
-        let transaction = conn.transaction().unwrap();
-
-        let mut statement = transaction.prepare(&format!(
-            "SELECT {0}_words.content
-            FROM {0}_words
-            JOIN {0}_types
-            ON {0}_types.id = {0}_words.type_id
-            WHERE {0}_words.word = ?
-            AND {0}_types.name = ?", &lang.code)
-        ).unwrap();
+    pub fn generate_entries(&self,
+                            transaction: &Transaction,
+                            entries: &WiktionaryEntries,
+                            progress: &dyn Progress) -> Result<(), DbError> {
+        let mut statement = transaction.prepare(
+            "SELECT words.content
+            FROM words
+            JOIN types
+            ON types.id = words.type_id
+            WHERE words.word = ?
+            AND types.name = ?"
+        )?;
+
+        for (i, entry) in entries.iter().enumerate() {
+            if i % COUNT_INTERVAL == 0 {
+                if progress.cancelled() {
+                    return Err(DbError::Cancelled);
+                }
+
+                progress.count(i, entries.len());
+            }
 
-        for entry in entries.iter() {
             if let Some(forms) = entry.parse_json()["forms"].as_array() {
                 let mut forms_vec: Vec<Form> = Vec::new();
 
@@ -203,22 +967,21 @@ impl WordDb {
                     forms_vec.push(form);
                 }
 
-                forms_vec = forms_vec.into_iter()
-                                     .filter(|x|
-                                         match &x.source {
-                                             Some(src) => src == "Declension" || src == "Conjugation",
-                                             None => false
-                                         }
-                                     ).collect();
+                forms_vec.retain(|x|
+                    match &x.source {
+                        Some(src) => src == "Declension" || src == "Conjugation",
+                        None => false
+                    }
+                );
 
                 forms_vec.sort_by_key(|x| x.form.clone());
 
                 let forms_group = forms_vec.group_by(|a, b| a.form == b.form);
 
                 for forms in forms_group.into_iter() {
-                    let mut entries = statement.query([&forms[0].form, &entry.type_]).unwrap();
+                    let mut entries = statement.query([&forms[0].form, &entry.type_])?;
 
-                    if let None = entries.next().unwrap() {
+                    if entries.next()?.is_none() {
                         let mut senses: Vec<Value> = Vec::new();
 
                         for form in forms {
@@ -255,20 +1018,18 @@ impl WordDb {
                                                              entry.type_.clone(),
                                                              entry_json.to_string());
 
-                        self.insert_entry(&transaction, lang, &new_entry);
+                        self.insert_entry(transaction, &new_entry)?;
                     }
                 }
             }
         }
 
-        drop(statement);
-        transaction.commit().unwrap();
-    }
+        progress.count(entries.len(), entries.len());
 
-    fn insert_types(&mut self, lang: &Language, entries: &WiktionaryEntries) {
-        let mut conn = self.connect();
-        let transaction = conn.transaction().unwrap();
+        Ok(())
+    }
 
+    fn insert_types(&self, transaction: &Transaction, entries: &WiktionaryEntries) -> rusqlite::Result<()> {
         let mut types = HashSet::new();
 
         for entry in entries.iter() {
@@ -276,73 +1037,431 @@ impl WordDb {
         }
 
         for type_ in types {
-            transaction.execute(&format!("
-            INSERT INTO {0}_types ( name )
-            VALUES (?)", &lang.code), [type_]).unwrap();
+            transaction.execute("
+            INSERT INTO types ( name )
+            VALUES (?)", [type_])?;
         }
 
-        transaction.commit().unwrap();
+        Ok(())
     }
 
-    fn insert_version(&mut self, lang: &Language) {
-        let mut conn = self.connect();
-        let transaction = conn.transaction().unwrap();
+    /// Register a language as installed in the catalog, or update it
+    fn insert_version(&self, lang: &Language, version: &Version, upgraded: i64) -> Result<(), DbError> {
+        let mut conn = self.connect()?;
+        let transaction = conn.transaction()?;
 
         transaction.execute("
-        INSERT INTO langs (code, name, major, minor, patch)
-        VALUES (?, ?, ?, ?, ?)
-        ", params![&lang.code, &lang.name, MAJOR, MINOR, PATCH]).unwrap();
+        CREATE TABLE IF NOT EXISTS langs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            code TINYTEXT UNIQUE NOT NULL,
+            name TINYTEXT NOT NULL,
+            major INTEGER NOT NULL,
+            minor INTEGER NOT NULL,
+            patch INTEGER NOT NULL,
+            upgraded INTEGER
+        )", [])?;
+
+        // Databases created before upgrades were timestamped lack this column.
+        // It fails harmlessly on newer ones
+        transaction.execute("ALTER TABLE langs ADD COLUMN upgraded INTEGER", []).ok();
+
+        transaction.execute("DELETE FROM langs WHERE code = ?", [&lang.code])?;
+
+        transaction.execute("
+        INSERT INTO langs (code, name, major, minor, patch, upgraded)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ", params![&lang.code, &lang.name, version.0, version.1, version.2, upgraded])?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Where a language's dump is downloaded from, as listed in the catalog
+    fn dump_url(&self, lang: &Language) -> String {
+        let url = match &lang.url {
+            Some(url) => Some(url.clone()),
+            // Installed languages don't carry it
+            None => self.get_lang(&lang.code).and_then(|lang| lang.url)
+        };
+
+        url.unwrap_or_else(|| self.settings.source_url(&lang.name))
+    }
+
+    pub fn cache_file(&self, lang: &Language) -> String {
+        format!("{}/{}.json", self.settings.cache_dir, lang.name)
+    }
 
-        transaction.commit().unwrap();
+    /// Remove a language's database and, optionally, its cached dump
+    pub fn remove_lang(&self, lang: &Language, remove_cache: bool) -> Result<(), DbError> {
+        let conn = self.connect()?;
+
+        self.lang_readers.lock().unwrap().remove(&lang.code);
+
+        // The file goes first, or `reconcile_langs` would register it again
+        // if removing it failed
+        self.remove_lang_file(&lang.code)?;
+
+        conn.execute("DELETE FROM langs WHERE code = ?", [&lang.code])?;
+
+        if remove_cache {
+            if let Err(e) = fs::remove_file(self.cache_file(lang)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        self.reload_langs();
+
+        Ok(())
     }
 
-    pub async fn upgrade_lang(&mut self, lang: &Language) -> Result<(), DbError> {
-        util::try_create_dir(DB_DIR);
+    pub async fn upgrade_lang(&self, lang: &Language, progress: &dyn Progress) -> Result<(), DbError> {
+        let progress = &PhaseLog::new(&lang.code, progress);
 
-        println!("Trying to read cached data...");
-        let cache_file = format!("{}/{}.json", CACHE_DIR, &lang.name);
+        util::try_create_dir(&self.langs_dir())?;
+
+        progress.phase("Trying to read cached data...");
+        let cache_file = self.cache_file(lang);
+        let url = self.dump_url(lang);
 
         let mut cached_data = File::open(&cache_file);
         let mut request = None;
 
-        if let Err(_) = cached_data {
-            let url = format!("https://kaikki.org/dictionary/{0}/kaikki.org-dictionary-{0}.json",
-                              &lang.name);
-            request = Some(reqwest::get(url));
+        if cached_data.is_err() {
+            request = Some(reqwest::get(&url));
         }
 
-        println!("Cleaning tables...");
-        self.clean_tables(lang)?;
-
         if let Some(request) = request {
             // Actually, the request was sent before
-            println!("Requesting data...");
-            let data = request.await.unwrap().text().await.unwrap();
+            progress.phase("Requesting data...");
+            let response = request.await.map_err(|e| DbError::Download(e.to_string()))?;
 
-            println!("Caching data...");
-            util::try_create_dir(CACHE_DIR);
-            fs::write(&cache_file, &data).unwrap();
+            if !response.status().is_success() {
+                return Err(DbError::Download(format!("{} answered {}", url, response.status())));
+            }
+
+            let data = response.text().await.map_err(|e| DbError::Download(e.to_string()))?;
+
+            progress.phase("Caching data...");
+            util::try_create_dir(&self.settings.cache_dir)?;
+
+            // A partial dump would be parsed as if it were whole next time
+            if let Err(e) = fs::write(&cache_file, &data) {
+                fs::remove_file(&cache_file).ok();
+                return Err(e.into());
+            }
 
             cached_data = File::open(&cache_file);
         }
 
-        println!("Parsing data...");
-        let entries = WiktionaryEntries::parse_data(cached_data.unwrap());
+        if progress.cancelled() {
+            return Err(DbError::Cancelled);
+        }
+
+        progress.phase("Parsing data...");
+        let entries = WiktionaryEntries::parse_data(cached_data?);
 
-        println!("Inserting types...");
-        self.insert_types(lang, &entries);
+        if progress.cancelled() {
+            return Err(DbError::Cancelled);
+        }
+
+        // Everything is written in a single transaction, so lookups keep
+        // seeing the previous version of the language until it's committed.
+        // Returning early, e.g. when cancelled, rolls it back
+        let mut conn = self.connect_lang(&lang.code)?;
+        let transaction = conn.transaction()?;
+
+        progress.phase("Cleaning tables...");
+        self.clean_tables(&transaction)?;
 
-        println!("Inserting entries...");
-        self.insert_entries(lang, &entries);
+        progress.phase("Inserting types...");
+        self.insert_types(&transaction, &entries)?;
 
-        println!("Generating \"form-of\" entries...");
-        self.generate_entries(lang, &entries);
+        progress.phase("Inserting entries...");
+        self.insert_entries(&transaction, &entries, progress)?;
+
+        progress.phase("Generating \"form-of\" entries...");
+        self.generate_entries(&transaction, &entries, progress)?;
+
+        if progress.cancelled() {
+            return Err(DbError::Cancelled);
+        }
 
-        println!("Inserting version...");
-        self.insert_version(lang);
+        let upgraded = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        Self::mark_installed(&transaction, lang, &Version::current(), upgraded)?;
 
-        println!("Done");
+        progress.phase("Committing...");
+        transaction.commit()?;
+
+        progress.phase("Inserting version...");
+        self.insert_version(lang, &Version::current(), upgraded)?;
+
+        progress.finish();
+        progress.progress.phase("Done");
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::{self, TempDir, KOT};
+
+    const KOTA: &str = r#"{"word": "kota", "pos": "noun", "senses": [{"form_of": [{"word": "kot"}], "tags": ["genitive", "form-of", "auto-generated"]}]}"#;
+
+    /// A language's database in memory, with the given entries
+    fn lang_db(entries: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        WordDb::create_lang_tables(&conn).unwrap();
+
+        conn.execute("INSERT INTO types (name) VALUES ('noun')", []).unwrap();
+
+        for entry in entries {
+            let word = WiktionaryEntry::parse(entry).word;
+            conn.execute("INSERT INTO words (word, type_id, content) VALUES (?, 1, ?)", [&word, *entry]).unwrap();
+        }
+
+        conn
+    }
+
+    #[test]
+    fn export_writes_entries_as_lines() {
+        let conn = lang_db(&[KOT, KOTA]);
+        let mut out = Vec::new();
+
+        assert_eq!(WordDb::export_entries(&conn, true, &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n{}\n", KOT, KOTA));
+    }
+
+    #[test]
+    fn export_skips_generated_entries() {
+        let conn = lang_db(&[KOT, KOTA]);
+        let mut out = Vec::new();
+
+        assert_eq!(WordDb::export_entries(&conn, false, &mut out).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", KOT));
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_seen_by_clones() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+
+        let lang = testing::install(&settings, &db.clone(), "pol").await;
+
+        assert_eq!(db.installed_langs().len(), 1);
+        assert_eq!(db.get_entries(&lang, "kot").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_processes_are_seen_after_reloading() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        // Doesn't share anything with `db`, like the CLI and the daemon
+        let other = WordDb::new(&settings, "inflectived.db");
+
+        assert!(!db.reload_langs());
+
+        let lang = testing::install(&settings, &other, "pol").await;
+
+        assert!(db.get_installed_lang("pol").is_none());
+        assert!(db.reload_langs());
+        assert_eq!(db.get_installed_lang("pol").unwrap().upgraded, lang.upgraded);
+        assert!(!db.reload_langs());
+
+        other.remove_lang(&lang, false).ok().unwrap();
+
+        assert!(db.reload_langs());
+        assert!(db.installed_langs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reloading_sees_reinstalled_languages() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let other = WordDb::new(&settings, "inflectived.db");
+
+        let lang = testing::install(&settings, &other, "pol").await;
+        db.reload_langs();
+        assert_eq!(db.get_entries(&lang, "kot").unwrap().len(), 1);
+
+        // The language's file is replaced, so pooled connections to the old
+        // one must go
+        other.remove_lang(&lang, false).ok().unwrap();
+        cache_dump_without_kot(&settings, &lang);
+        // Upgrades are told apart by their time, in seconds
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        other.upgrade_lang(&lang, &NoProgress).await.ok().unwrap();
+
+        assert!(db.reload_langs());
+        assert!(db.get_entries(&lang, "kot").unwrap().is_empty());
+        assert_eq!(db.get_entries(&lang, "pies").unwrap().len(), 1);
+    }
+
+    fn cache_dump_without_kot(settings: &Settings, lang: &Language) {
+        let dump: String = testing::DUMP.lines()
+                                        .filter(|line| !line.contains(r#""word": "kot""#))
+                                        .map(|line| format!("{}\n", line))
+                                        .collect();
+
+        testing::cache_dump(settings, &lang.name, &dump);
+    }
+
+    #[test]
+    fn writers_give_up_while_the_database_is_locked() {
+        let dir = TempDir::new();
+        let db = WordDb::new(&testing::settings(&dir), "inflectived.db");
+
+        // Like an upgrade holding its transaction
+        let mut conn = db.connect().unwrap();
+        let transaction = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+
+        assert!(matches!(db.set_admin_password_hash("hash"), Err(DbError::Busy)));
+        assert!(matches!(db.add_api_key("test", "hash", 1.0, 1, None), Err(DbError::Busy)));
+
+        transaction.rollback().unwrap();
+
+        assert!(db.set_admin_password_hash("hash").is_ok());
+    }
+
+    #[tokio::test]
+    async fn installs_the_catalog_missed_are_registered() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        // Like being killed between committing the upgrade and the catalog
+        db.connect().unwrap().execute("DELETE FROM langs", []).unwrap();
+        db.reload_langs();
+        assert!(db.get_installed_lang("pol").is_none());
+
+        db.recover();
+
+        let recovered = db.get_installed_lang("pol").unwrap();
+        assert_eq!(recovered.name, lang.name);
+        assert_eq!(recovered.version, lang.version);
+        assert_eq!(recovered.upgraded, lang.upgraded);
+
+        // Or between upgrading an installed language and the catalog
+        db.connect().unwrap().execute("UPDATE langs SET upgraded = 0", []).unwrap();
+        db.reload_langs();
+
+        db.recover();
+
+        assert_eq!(db.get_installed_lang("pol").unwrap().upgraded, lang.upgraded);
+    }
+
+    #[tokio::test]
+    async fn removed_languages_stay_removed() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        db.remove_lang(&lang, false).ok().unwrap();
+        db.recover();
+
+        assert!(db.installed_langs().is_empty());
+        assert!(db.lang_files().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_errors() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Read the request before answering, or the client might see a reset
+            io::Read::read(&mut stream, &mut [0; 1024]).unwrap();
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+
+        let mut lang = db.get_lang("pol").unwrap();
+        lang.url = Some(format!("http://{}/Polish.json", address));
+
+        let error = db.upgrade_lang(&lang, &NoProgress).await.err().unwrap();
+        assert!(matches!(&error, DbError::Download(e) if e.contains("404")), "{}", error);
+        assert!(!Path::new(&db.cache_file(&lang)).exists());
+
+        // Nothing listens there anymore
+        let error = db.upgrade_lang(&lang, &NoProgress).await.err().unwrap();
+        assert!(matches!(error, DbError::Download(_)), "{}", error);
+        assert!(db.installed_langs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn incomplete_restores_are_rolled_back() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        let copy = format!("{}/copy.db", dir.0);
+        db.snapshot_lang(&lang, &copy).unwrap();
+
+        let result = db.restore_lang(&lang, &copy, &Version::current(), 0, 7);
+
+        assert!(matches!(result, Err(DbError::Incomplete { restored: 6, expected: 7 })));
+        assert_eq!(db.get_installed_lang("pol").unwrap().upgraded, lang.upgraded);
+        assert_eq!(db.get_entries(&lang, "kot").unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failing_removals_are_errors() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        // Something that can't be removed as a file where the dump goes
+        fs::remove_file(db.cache_file(&lang)).unwrap();
+        fs::create_dir(db.cache_file(&lang)).unwrap();
+
+        assert!(matches!(db.remove_lang(&lang, true), Err(DbError::Io(_))));
+    }
+
+    #[test]
+    fn languages_are_moved_out_of_old_catalogs_on_recovery() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+
+        testing::legacy_catalog(&db);
+        let catalog = db.connect().unwrap();
+
+        // Opening the database alone leaves the catalog as it is
+        let db = WordDb::new(&settings, "inflectived.db");
+        assert!(db.lang_files().is_empty());
+
+        db.recover();
+
+        assert_eq!(db.lang_files(), ["pol"]);
+        assert_eq!(db.get_entries(&db.get_installed_lang("pol").unwrap(), "kot").unwrap(), [KOT]);
+
+        let tables: i64 = catalog.query_row("SELECT count(*) FROM sqlite_master WHERE name LIKE 'pol_%'",
+                                            [],
+                                            |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn export_fails_on_database_errors() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        assert!(WordDb::export_entries(&conn, true, Vec::new()).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::io::{BufRead, BufReader};
 use std::cmp;
 use std::slice::Iter;
 use serde_json::Value;
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 
 #[derive (Clone, Debug)]
 pub struct WiktionaryEntry {
@@ -59,6 +59,26 @@ impl WiktionaryEntry {
     pub fn parse_json(&self) -> Value {
         serde_json::from_str(&self.unparsed_json).unwrap()
     }
+
+    /// Whether an entry was made up by `WordDb::generate_entries`
+    pub fn is_generated(unparsed_json: &str) -> bool {
+        // Cheap check first, as this runs over whole databases
+        if !unparsed_json.contains("\"auto-generated\"") {
+            return false;
+        }
+
+        let json: Value = serde_json::from_str(unparsed_json).unwrap();
+
+        match json["senses"].as_array() {
+            Some(senses) => senses.iter().all(|sense|
+                match sense["tags"].as_array() {
+                    Some(tags) => tags.iter().any(|tag| tag == "auto-generated"),
+                    None => false
+                }
+            ),
+            None => false
+        }
+    }
 }
 
 pub struct WiktionaryEntries(Vec<WiktionaryEntry>);
@@ -76,15 +96,47 @@ impl WiktionaryEntries {
         Self(entries)
     }
 
-    pub fn iter(&self) -> Iter<WiktionaryEntry> {
+    pub fn iter(&self) -> Iter<'_, WiktionaryEntry> {
         self.0.iter()
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Form {
     pub form: String,
     pub tags: Option<Vec<String>>,
     pub source: Option<String>,
 }
 
+/// The commonly used fields of an entry in a kaikki.org dump
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub word: String,
+    pub pos: String,
+    pub senses: Option<Vec<Sense>>,
+    pub forms: Option<Vec<Form>>,
+    pub sounds: Option<Vec<Sound>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sense {
+    pub glosses: Option<Vec<String>>,
+    pub tags: Option<Vec<String>>,
+    pub form_of: Option<Vec<FormOf>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FormOf {
+    pub word: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sound {
+    pub ipa: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
@@ -0,0 +1,202 @@
+//! Liveness and readiness checks, for load balancers and orchestrators
+
+use rocket::get;
+use rocket::State;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::database::WordDb;
+use crate::jobs::Jobs;
+
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LangState {
+    Ok,
+    /// The language is installed but its database or tables are gone
+    MissingTables,
+    /// The language is installed but has no entries
+    Empty,
+    /// The language has a database but isn't installed, e.g. because an
+    /// upgrade was interrupted
+    Incomplete,
+    /// The language is being installed by the daemon
+    Installing,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct LangHealth {
+    pub code: String,
+    pub state: LangState,
+}
+
+/// Result of the readiness checks
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Why the database couldn't be read, if it couldn't
+    pub database_error: Option<String>,
+    pub langs: Vec<LangHealth>,
+}
+
+/// Check that the daemon is running
+#[utoipa::path(
+    tag = "health",
+    responses(
+        (status = 200, description = "The daemon is running", body = String, content_type = "text/plain")
+    )
+)]
+#[get("/healthz")]
+pub fn healthz() -> &'static str {
+    "OK"
+}
+
+/// Check that the database and every installed language can be read
+///
+/// Language databases left over by interrupted upgrades are reported, but
+/// don't stop lookups from being served.
+#[utoipa::path(
+    tag = "health",
+    responses(
+        (status = 200, description = "Lookups can be served", body = Readiness),
+        (status = 503, description = "The database can't be read or an installed language is broken", body = Readiness)
+    )
+)]
+#[get("/readyz")]
+pub fn readyz(db: &State<WordDb>, jobs: &State<Jobs>) -> status::Custom<Json<Readiness>> {
+    if let Err(e) = db.check_catalog() {
+        return status::Custom(Status::ServiceUnavailable, Json(Readiness {
+            ready: false,
+            database_error: Some(e.to_string()),
+            langs: Vec::new(),
+        }));
+    }
+
+    let installed_langs = db.installed_langs();
+    let lang_files = db.lang_files();
+    let mut langs = Vec::new();
+
+    for lang in installed_langs.iter() {
+        let state = if !lang_files.contains(&lang.code) {
+            LangState::MissingTables
+        } else {
+            match db.table_stats(&lang.code) {
+                Ok(tables) => match (tables.get("words"), tables.get("types")) {
+                    (Some(true), Some(true)) => LangState::Ok,
+                    (Some(_), Some(_)) => LangState::Empty,
+                    _ => LangState::MissingTables
+                },
+                Err(_) => LangState::MissingTables
+            }
+        };
+
+        langs.push(LangHealth { code: lang.code.clone(), state });
+    }
+
+    // Leftovers of upgrades that didn't get to register their language,
+    // unless they're still at it
+    for code in lang_files {
+        if !installed_langs.iter().any(|lang| lang.code == code) {
+            let state = match jobs.running(&code) {
+                Some(_) => LangState::Installing,
+                None => LangState::Incomplete
+            };
+
+            langs.push(LangHealth { code, state });
+        }
+    }
+
+    langs.sort_by(|a, b| a.code.cmp(&b.code));
+
+    let ready = langs.iter().all(|lang| lang.state != LangState::MissingTables && lang.state != LangState::Empty);
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+
+    status::Custom(status, Json(Readiness {
+        ready,
+        database_error: None,
+        langs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+    use crate::language::Language;
+    use crate::testing::{self, TempDir};
+
+    async fn readiness(client: &Client) -> (Status, serde_json::Value) {
+        let response = client.get("/readyz").dispatch().await;
+
+        (response.status(), response.into_json().await.unwrap())
+    }
+
+    #[rocket::async_test]
+    async fn ready_with_nothing_installed() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        assert_eq!(client.get("/healthz").dispatch().await.into_string().await.as_deref(), Some("OK"));
+
+        let (status, body) = readiness(&client).await;
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["langs"], serde_json::json!([]));
+    }
+
+    #[rocket::async_test]
+    async fn leftover_databases_dont_fail_readiness() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        // As left by upgrades that didn't get to register their language
+        db.connect_lang("ger").unwrap();
+        db.connect_lang("fre").unwrap();
+        client.rocket().state::<Jobs>().unwrap().add_running(&Language::new("fre", "French"));
+
+        let (status, body) = readiness(&client).await;
+
+        assert_eq!(status, Status::Ok);
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["langs"], serde_json::json!([
+            {"code": "fre", "state": "installing"},
+            {"code": "ger", "state": "incomplete"},
+            {"code": "pol", "state": "ok"},
+        ]));
+    }
+
+    #[rocket::async_test]
+    async fn broken_languages_fail_readiness() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        db.connect_lang("pol").unwrap().execute("DELETE FROM words", []).unwrap();
+
+        let (status, body) = readiness(&client).await;
+
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["langs"][0]["state"], "empty");
+
+        std::fs::remove_file(db.lang_path("pol")).unwrap();
+
+        let (status, body) = readiness(&client).await;
+
+        assert_eq!(status, Status::ServiceUnavailable);
+        assert_eq!(body["langs"][0]["state"], "missing_tables");
+    }
+}
@@ -0,0 +1,342 @@
+//! ISO 639 language codes, so languages can be asked for by any of their
+//! standard codes, e.g. "fr", "fre" or "fra" for French
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Standard codes of a language, as far as it has them
+#[derive(Serialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct Codes {
+    pub iso639_1: Option<String>,
+    /// Bibliographic code, e.g. "fre"
+    pub iso639_2b: Option<String>,
+    /// Terminological code, e.g. "fra"
+    pub iso639_2t: Option<String>,
+    pub iso639_3: Option<String>,
+}
+
+impl Codes {
+    /// Whether any of the codes is `code`, ignoring case
+    pub fn matches(&self, code: &str) -> bool {
+        [&self.iso639_1, &self.iso639_2b, &self.iso639_2t, &self.iso639_3].iter()
+                                                                           .filter_map(|variant| variant.as_ref())
+                                                                           .any(|variant| variant.eq_ignore_ascii_case(code))
+    }
+
+    /// Code to install the language under, the bibliographic one when
+    /// there's a choice, like the built-in languages
+    pub fn preferred(&self) -> Option<&str> {
+        self.iso639_2b.as_deref().or(self.iso639_3.as_deref())
+    }
+}
+
+struct Entry {
+    /// English name, as used by Wiktionary
+    name: &'static str,
+    part1: Option<&'static str>,
+    part2b: Option<&'static str>,
+    part2t: Option<&'static str>,
+    part3: &'static str,
+}
+
+/// Languages with two-letter codes, where the terminological and ISO 639-3
+/// codes are the same
+const fn two(name: &'static str, part1: &'static str, part2b: &'static str, part2t: &'static str) -> Entry {
+    Entry { name, part1: Some(part1), part2b: Some(part2b), part2t: Some(part2t), part3: part2t }
+}
+
+/// Languages with three-letter codes only
+const fn three(name: &'static str, part2: Option<&'static str>, part3: &'static str) -> Entry {
+    Entry { name, part1: None, part2b: part2, part2t: part2, part3 }
+}
+
+const LANGS: &[Entry] = &[
+    two("Afar", "aa", "aar", "aar"),
+    two("Abkhaz", "ab", "abk", "abk"),
+    two("Avestan", "ae", "ave", "ave"),
+    two("Afrikaans", "af", "afr", "afr"),
+    two("Akan", "ak", "aka", "aka"),
+    two("Amharic", "am", "amh", "amh"),
+    two("Aragonese", "an", "arg", "arg"),
+    two("Arabic", "ar", "ara", "ara"),
+    two("Assamese", "as", "asm", "asm"),
+    two("Avar", "av", "ava", "ava"),
+    two("Aymara", "ay", "aym", "aym"),
+    two("Azerbaijani", "az", "aze", "aze"),
+    two("Bashkir", "ba", "bak", "bak"),
+    two("Belarusian", "be", "bel", "bel"),
+    two("Bulgarian", "bg", "bul", "bul"),
+    two("Bislama", "bi", "bis", "bis"),
+    two("Bambara", "bm", "bam", "bam"),
+    two("Bengali", "bn", "ben", "ben"),
+    two("Tibetan", "bo", "tib", "bod"),
+    two("Breton", "br", "bre", "bre"),
+    two("Bosnian", "bs", "bos", "bos"),
+    two("Catalan", "ca", "cat", "cat"),
+    two("Chechen", "ce", "che", "che"),
+    two("Chamorro", "ch", "cha", "cha"),
+    two("Corsican", "co", "cos", "cos"),
+    two("Cree", "cr", "cre", "cre"),
+    two("Czech", "cs", "cze", "ces"),
+    two("Old Church Slavonic", "cu", "chu", "chu"),
+    two("Chuvash", "cv", "chv", "chv"),
+    two("Welsh", "cy", "wel", "cym"),
+    two("Danish", "da", "dan", "dan"),
+    two("German", "de", "ger", "deu"),
+    two("Dhivehi", "dv", "div", "div"),
+    two("Dzongkha", "dz", "dzo", "dzo"),
+    two("Ewe", "ee", "ewe", "ewe"),
+    two("Greek", "el", "gre", "ell"),
+    two("English", "en", "eng", "eng"),
+    two("Esperanto", "eo", "epo", "epo"),
+    two("Spanish", "es", "spa", "spa"),
+    two("Estonian", "et", "est", "est"),
+    two("Basque", "eu", "baq", "eus"),
+    two("Persian", "fa", "per", "fas"),
+    two("Fula", "ff", "ful", "ful"),
+    two("Finnish", "fi", "fin", "fin"),
+    two("Fijian", "fj", "fij", "fij"),
+    two("Faroese", "fo", "fao", "fao"),
+    two("French", "fr", "fre", "fra"),
+    two("West Frisian", "fy", "fry", "fry"),
+    two("Irish", "ga", "gle", "gle"),
+    two("Scottish Gaelic", "gd", "gla", "gla"),
+    two("Galician", "gl", "glg", "glg"),
+    two("Guarani", "gn", "grn", "grn"),
+    two("Gujarati", "gu", "guj", "guj"),
+    two("Manx", "gv", "glv", "glv"),
+    two("Hausa", "ha", "hau", "hau"),
+    two("Hebrew", "he", "heb", "heb"),
+    two("Hindi", "hi", "hin", "hin"),
+    two("Hiri Motu", "ho", "hmo", "hmo"),
+    two("Croatian", "hr", "hrv", "hrv"),
+    two("Haitian Creole", "ht", "hat", "hat"),
+    two("Hungarian", "hu", "hun", "hun"),
+    two("Armenian", "hy", "arm", "hye"),
+    two("Herero", "hz", "her", "her"),
+    two("Interlingua", "ia", "ina", "ina"),
+    two("Indonesian", "id", "ind", "ind"),
+    two("Interlingue", "ie", "ile", "ile"),
+    two("Igbo", "ig", "ibo", "ibo"),
+    two("Sichuan Yi", "ii", "iii", "iii"),
+    two("Inupiaq", "ik", "ipk", "ipk"),
+    two("Ido", "io", "ido", "ido"),
+    two("Icelandic", "is", "ice", "isl"),
+    two("Italian", "it", "ita", "ita"),
+    two("Inuktitut", "iu", "iku", "iku"),
+    two("Japanese", "ja", "jpn", "jpn"),
+    two("Javanese", "jv", "jav", "jav"),
+    two("Georgian", "ka", "geo", "kat"),
+    two("Kongo", "kg", "kon", "kon"),
+    two("Kikuyu", "ki", "kik", "kik"),
+    two("Kwanyama", "kj", "kua", "kua"),
+    two("Kazakh", "kk", "kaz", "kaz"),
+    two("Greenlandic", "kl", "kal", "kal"),
+    two("Khmer", "km", "khm", "khm"),
+    two("Kannada", "kn", "kan", "kan"),
+    two("Korean", "ko", "kor", "kor"),
+    two("Kanuri", "kr", "kau", "kau"),
+    two("Kashmiri", "ks", "kas", "kas"),
+    two("Kurdish", "ku", "kur", "kur"),
+    two("Komi", "kv", "kom", "kom"),
+    two("Cornish", "kw", "cor", "cor"),
+    two("Kyrgyz", "ky", "kir", "kir"),
+    two("Latin", "la", "lat", "lat"),
+    two("Luxembourgish", "lb", "ltz", "ltz"),
+    two("Luganda", "lg", "lug", "lug"),
+    two("Limburgish", "li", "lim", "lim"),
+    two("Lingala", "ln", "lin", "lin"),
+    two("Lao", "lo", "lao", "lao"),
+    two("Lithuanian", "lt", "lit", "lit"),
+    two("Luba-Katanga", "lu", "lub", "lub"),
+    two("Latvian", "lv", "lav", "lav"),
+    two("Malagasy", "mg", "mlg", "mlg"),
+    two("Marshallese", "mh", "mah", "mah"),
+    two("Maori", "mi", "mao", "mri"),
+    two("Macedonian", "mk", "mac", "mkd"),
+    two("Malayalam", "ml", "mal", "mal"),
+    two("Mongolian", "mn", "mon", "mon"),
+    two("Marathi", "mr", "mar", "mar"),
+    two("Malay", "ms", "may", "msa"),
+    two("Maltese", "mt", "mlt", "mlt"),
+    two("Burmese", "my", "bur", "mya"),
+    two("Nauruan", "na", "nau", "nau"),
+    two("Norwegian Bokmål", "nb", "nob", "nob"),
+    two("Northern Ndebele", "nd", "nde", "nde"),
+    two("Nepali", "ne", "nep", "nep"),
+    two("Ndonga", "ng", "ndo", "ndo"),
+    two("Dutch", "nl", "dut", "nld"),
+    two("Norwegian Nynorsk", "nn", "nno", "nno"),
+    two("Norwegian", "no", "nor", "nor"),
+    two("Southern Ndebele", "nr", "nbl", "nbl"),
+    two("Navajo", "nv", "nav", "nav"),
+    two("Chichewa", "ny", "nya", "nya"),
+    two("Occitan", "oc", "oci", "oci"),
+    two("Ojibwe", "oj", "oji", "oji"),
+    two("Oromo", "om", "orm", "orm"),
+    two("Odia", "or", "ori", "ori"),
+    two("Ossetian", "os", "oss", "oss"),
+    two("Punjabi", "pa", "pan", "pan"),
+    two("Pali", "pi", "pli", "pli"),
+    two("Polish", "pl", "pol", "pol"),
+    two("Pashto", "ps", "pus", "pus"),
+    two("Portuguese", "pt", "por", "por"),
+    two("Quechua", "qu", "que", "que"),
+    two("Romansch", "rm", "roh", "roh"),
+    two("Rundi", "rn", "run", "run"),
+    two("Romanian", "ro", "rum", "ron"),
+    two("Russian", "ru", "rus", "rus"),
+    two("Rwanda-Rundi", "rw", "kin", "kin"),
+    two("Sanskrit", "sa", "san", "san"),
+    two("Sardinian", "sc", "srd", "srd"),
+    two("Sindhi", "sd", "snd", "snd"),
+    two("Northern Sami", "se", "sme", "sme"),
+    two("Sango", "sg", "sag", "sag"),
+    two("Sinhalese", "si", "sin", "sin"),
+    two("Slovak", "sk", "slo", "slk"),
+    two("Slovene", "sl", "slv", "slv"),
+    two("Samoan", "sm", "smo", "smo"),
+    two("Shona", "sn", "sna", "sna"),
+    two("Somali", "so", "som", "som"),
+    two("Albanian", "sq", "alb", "sqi"),
+    two("Serbian", "sr", "srp", "srp"),
+    two("Swazi", "ss", "ssw", "ssw"),
+    two("Sotho", "st", "sot", "sot"),
+    two("Sundanese", "su", "sun", "sun"),
+    two("Swedish", "sv", "swe", "swe"),
+    two("Swahili", "sw", "swa", "swa"),
+    two("Tamil", "ta", "tam", "tam"),
+    two("Telugu", "te", "tel", "tel"),
+    two("Tajik", "tg", "tgk", "tgk"),
+    two("Thai", "th", "tha", "tha"),
+    two("Tigrinya", "ti", "tir", "tir"),
+    two("Turkmen", "tk", "tuk", "tuk"),
+    two("Tagalog", "tl", "tgl", "tgl"),
+    two("Tswana", "tn", "tsn", "tsn"),
+    two("Tongan", "to", "ton", "ton"),
+    two("Turkish", "tr", "tur", "tur"),
+    two("Tsonga", "ts", "tso", "tso"),
+    two("Tatar", "tt", "tat", "tat"),
+    two("Twi", "tw", "twi", "twi"),
+    two("Tahitian", "ty", "tah", "tah"),
+    two("Uyghur", "ug", "uig", "uig"),
+    two("Ukrainian", "uk", "ukr", "ukr"),
+    two("Urdu", "ur", "urd", "urd"),
+    two("Uzbek", "uz", "uzb", "uzb"),
+    two("Venda", "ve", "ven", "ven"),
+    two("Vietnamese", "vi", "vie", "vie"),
+    two("Volapük", "vo", "vol", "vol"),
+    two("Walloon", "wa", "wln", "wln"),
+    two("Wolof", "wo", "wol", "wol"),
+    two("Xhosa", "xh", "xho", "xho"),
+    two("Yiddish", "yi", "yid", "yid"),
+    two("Yoruba", "yo", "yor", "yor"),
+    two("Zhuang", "za", "zha", "zha"),
+    two("Chinese", "zh", "chi", "zho"),
+    two("Zulu", "zu", "zul", "zul"),
+    // Wiktionary has plenty of dictionaries for languages without two-letter
+    // codes, historical ones especially
+    three("Akkadian", Some("akk"), "akk"),
+    three("Ancient Greek", Some("grc"), "grc"),
+    three("Aramaic", Some("arc"), "arc"),
+    three("Asturian", Some("ast"), "ast"),
+    three("Cantonese", None, "yue"),
+    three("Cebuano", Some("ceb"), "ceb"),
+    three("Coptic", Some("cop"), "cop"),
+    three("Egyptian", Some("egy"), "egy"),
+    three("Friulian", Some("fur"), "fur"),
+    three("Gothic", Some("got"), "got"),
+    three("Hawaiian", Some("haw"), "haw"),
+    three("Kashubian", Some("csb"), "csb"),
+    three("Ladino", Some("lad"), "lad"),
+    three("Lojban", Some("jbo"), "jbo"),
+    three("Low German", Some("nds"), "nds"),
+    three("Lower Sorbian", Some("dsb"), "dsb"),
+    three("Middle English", Some("enm"), "enm"),
+    three("Middle French", Some("frm"), "frm"),
+    three("Middle High German", Some("gmh"), "gmh"),
+    three("Neapolitan", Some("nap"), "nap"),
+    three("Old English", Some("ang"), "ang"),
+    three("Old French", Some("fro"), "fro"),
+    three("Old High German", Some("goh"), "goh"),
+    three("Old Irish", Some("sga"), "sga"),
+    three("Old Norse", Some("non"), "non"),
+    three("Ottoman Turkish", Some("ota"), "ota"),
+    three("Serbo-Croatian", None, "hbs"),
+    three("Sicilian", Some("scn"), "scn"),
+    three("Sumerian", Some("sux"), "sux"),
+    three("Tok Pisin", Some("tpi"), "tpi"),
+    three("Upper Sorbian", Some("hsb"), "hsb"),
+    three("Venetian", None, "vec"),
+    three("Yakut", Some("sah"), "sah"),
+];
+
+impl Entry {
+    fn matches(&self, code: &str) -> bool {
+        [self.part1, self.part2b, self.part2t, Some(self.part3)].iter()
+                                                                .flatten()
+                                                                .any(|variant| variant.eq_ignore_ascii_case(code))
+    }
+
+    fn codes(&self) -> Codes {
+        Codes {
+            iso639_1: self.part1.map(String::from),
+            iso639_2b: self.part2b.map(String::from),
+            iso639_2t: self.part2t.map(String::from),
+            iso639_3: Some(String::from(self.part3)),
+        }
+    }
+}
+
+/// Codes of the language that has `code` as one of its codes
+pub fn by_code(code: &str) -> Option<Codes> {
+    LANGS.iter()
+         .find(|entry| entry.matches(code))
+         .map(Entry::codes)
+}
+
+/// Codes of the language with the given English name
+pub fn by_name(name: &str) -> Option<Codes> {
+    LANGS.iter()
+         .find(|entry| entry.name.eq_ignore_ascii_case(name))
+         .map(Entry::codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn languages_are_found_by_any_code() {
+        for code in ["bo", "tib", "bod", "BOD"] {
+            assert_eq!(by_code(code).unwrap().iso639_1.as_deref(), Some("bo"), "{}", code);
+        }
+
+        assert_eq!(by_code("vec").unwrap().preferred(), Some("vec"));
+        assert!(by_code("xx").is_none());
+        assert!(by_code("").is_none());
+    }
+
+    #[test]
+    fn languages_are_found_by_name() {
+        let codes = by_name("tibetan").unwrap();
+
+        assert_eq!(codes, Codes {
+            iso639_1: Some(String::from("bo")),
+            iso639_2b: Some(String::from("tib")),
+            iso639_2t: Some(String::from("bod")),
+            iso639_3: Some(String::from("bod")),
+        });
+        assert_eq!(codes.preferred(), Some("tib"));
+        assert!(by_name("Klingon").is_none());
+    }
+
+    #[test]
+    fn three_letter_languages_have_no_two_letter_code() {
+        let codes = by_name("Yakut").unwrap();
+
+        assert_eq!(codes.iso639_1, None);
+        assert_eq!(codes.iso639_2b.as_deref(), Some("sah"));
+        assert_eq!(codes.iso639_3.as_deref(), Some("sah"));
+    }
+}
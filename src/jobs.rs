@@ -0,0 +1,500 @@
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::database::{WordDb, DbError, Progress};
+use crate::language::Language;
+use crate::metrics::UpgradeDurations;
+
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped before finishing, e.g. because the daemon shut down. Nothing
+    /// was written
+    Cancelled,
+}
+
+/// Status of a language upgrade running in the daemon
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct JobStatus {
+    pub id: u64,
+    /// Code of the language being upgraded
+    pub lang: String,
+    pub state: JobState,
+    /// Last phase reported by the upgrade, e.g. "Inserting entries..."
+    pub phase: String,
+    /// Entries processed so far in the current phase, if it counts them
+    pub done: Option<usize>,
+    pub total: Option<usize>,
+    pub error: Option<String>,
+    /// Unix time the job started at
+    pub started: i64,
+    pub finished: Option<i64>,
+}
+
+/// A change in a job's status, carrying the new status
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Phase(JobStatus),
+    Progress(JobStatus),
+    Finished(JobStatus),
+}
+
+pub struct Job {
+    status: Mutex<JobStatus>,
+    events: broadcast::Sender<JobEvent>,
+    cancelled: AtomicBool,
+}
+
+impl Job {
+    pub fn status(&self) -> JobStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Receive the events sent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    /// Ask the upgrade to stop. It rolls back what it wrote so far
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn finish(&self, state: JobState, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+
+        status.finished = Some(now());
+        status.state = state;
+        status.error = error;
+
+        // Nobody listening is fine
+        self.events.send(JobEvent::Finished(status.clone())).ok();
+    }
+}
+
+impl Progress for Job {
+    fn phase(&self, phase: &str) {
+        let mut status = self.status.lock().unwrap();
+
+        status.phase = String::from(phase);
+        status.done = None;
+        status.total = None;
+
+        self.events.send(JobEvent::Phase(status.clone())).ok();
+    }
+
+    fn count(&self, done: usize, total: usize) {
+        let mut status = self.status.lock().unwrap();
+
+        status.done = Some(done);
+        status.total = Some(total);
+
+        self.events.send(JobEvent::Progress(status.clone())).ok();
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+pub enum JobError {
+    /// Another job is already upgrading the language
+    AlreadyRunning(u64),
+    /// The language is being removed
+    Removing,
+}
+
+/// A language kept from being upgraded while it's removed, released when
+/// dropped
+pub struct Removal<'a> {
+    jobs: &'a Jobs,
+    lang: String,
+}
+
+impl Drop for Removal<'_> {
+    fn drop(&mut self) {
+        self.jobs.removing.lock().unwrap().remove(&self.lang);
+    }
+}
+
+/// Finished jobs kept around for clients to check on, newest first
+const KEPT_FINISHED: usize = 100;
+
+/// Upgrade jobs started in the daemon, including the last finished ones
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+    /// Codes of the languages being removed. Locked after `jobs`, when both
+    /// are
+    removing: Mutex<HashSet<String>>,
+    pub durations: Arc<UpgradeDurations>,
+}
+
+impl Jobs {
+    pub fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Id of the job upgrading a language, if any is running
+    pub fn running(&self, lang: &str) -> Option<u64> {
+        Self::find_running(&self.jobs.lock().unwrap(), lang)
+    }
+
+    fn find_running(jobs: &HashMap<u64, Arc<Job>>, lang: &str) -> Option<u64> {
+        jobs.values()
+            .map(|job| job.status())
+            .find(|status| status.lang == lang && status.state == JobState::Running)
+            .map(|status| status.id)
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        let mut statuses: Vec<JobStatus> = self.jobs.lock()
+                                               .unwrap()
+                                               .values()
+                                               .map(|job| job.status())
+                                               .collect();
+
+        statuses.sort_by_key(|status| status.id);
+
+        statuses
+    }
+
+    /// Cancel every running job
+    pub fn cancel_all(&self) {
+        for job in self.jobs.lock().unwrap().values() {
+            job.cancel();
+        }
+    }
+
+    /// Wait until no job is running, or `timeout` passed. Returns whether
+    /// all jobs finished
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+
+        loop {
+            let running = self.list().iter().any(|status| status.state == JobState::Running);
+
+            if !running {
+                return true;
+            }
+
+            if started.elapsed() >= timeout {
+                return false;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Forget the oldest finished jobs, beyond the last `KEPT_FINISHED`
+    fn prune(jobs: &mut HashMap<u64, Arc<Job>>) {
+        let mut finished: Vec<u64> = jobs.values()
+                                         .map(|job| job.status())
+                                         .filter(|status| status.state != JobState::Running)
+                                         .map(|status| status.id)
+                                         .collect();
+
+        if finished.len() > KEPT_FINISHED {
+            finished.sort_unstable();
+
+            for id in &finished[..finished.len() - KEPT_FINISHED] {
+                jobs.remove(id);
+            }
+        }
+    }
+
+    fn add_job(&self, jobs: &mut HashMap<u64, Arc<Job>>, lang: &Language, phase: &str) -> Arc<Job> {
+        Self::prune(jobs);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let job = Arc::new(Job {
+            status: Mutex::new(JobStatus {
+                id,
+                lang: lang.code.clone(),
+                state: JobState::Running,
+                phase: String::from(phase),
+                done: None,
+                total: None,
+                error: None,
+                started: now(),
+                finished: None,
+            }),
+            // Slow listeners skip events rather than holding up the upgrade
+            events: broadcast::channel(64).0,
+            cancelled: AtomicBool::new(false),
+        });
+
+        jobs.insert(id, job.clone());
+
+        job
+    }
+
+    /// Keep a language from being upgraded until the returned guard is
+    /// dropped, unless an upgrade is already running
+    pub fn start_removal(&self, lang: &Language) -> Result<Removal<'_>, JobError> {
+        let jobs = self.jobs.lock().unwrap();
+
+        if let Some(id) = Self::find_running(&jobs, &lang.code) {
+            return Err(JobError::AlreadyRunning(id));
+        }
+
+        if !self.removing.lock().unwrap().insert(lang.code.clone()) {
+            return Err(JobError::Removing);
+        }
+
+        Ok(Removal { jobs: self, lang: lang.code.clone() })
+    }
+
+    /// Start upgrading a language in the background, returning the job
+    pub fn start_upgrade(&self, db: &WordDb, lang: Language) -> Result<Arc<Job>, JobError> {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        if let Some(id) = Self::find_running(&jobs, &lang.code) {
+            return Err(JobError::AlreadyRunning(id));
+        }
+
+        if self.removing.lock().unwrap().contains(&lang.code) {
+            return Err(JobError::Removing);
+        }
+
+        let job = self.add_job(&mut jobs, &lang, "Starting...");
+
+        let db = db.clone();
+        let thread_job = job.clone();
+        let durations = self.durations.clone();
+
+        // Upgrades block for minutes while parsing and inserting, so they get
+        // a thread of their own instead of tying up one of Rocket's workers
+        thread::spawn(move || run_upgrade(&db, &lang, &thread_job, &durations));
+
+        Ok(job)
+    }
+
+    /// Upgrade languages one after another in the background, so only one
+    /// dump is held in memory at a time. Languages already being upgraded or
+    /// being removed are skipped
+    pub fn start_upgrades(&self, db: &WordDb, langs: Vec<Language>) -> Vec<Arc<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let removing = self.removing.lock().unwrap().clone();
+        let mut queue = Vec::new();
+
+        for lang in langs {
+            if Self::find_running(&jobs, &lang.code).is_none() && !removing.contains(&lang.code) {
+                let job = self.add_job(&mut jobs, &lang, "Waiting for other upgrades...");
+                queue.push((lang, job));
+            }
+        }
+
+        let db = db.clone();
+        let durations = self.durations.clone();
+        let started = queue.iter().map(|(_, job)| job.clone()).collect();
+
+        thread::spawn(move || {
+            for (lang, job) in queue {
+                if job.cancelled() {
+                    job.finish(JobState::Cancelled, None);
+                    continue;
+                }
+
+                run_upgrade(&db, &lang, &job, &durations);
+            }
+        });
+
+        started
+    }
+}
+
+/// Upgrade a language, blocking until done, and report the outcome to its job
+fn run_upgrade(db: &WordDb, lang: &Language, job: &Arc<Job>, durations: &UpgradeDurations) {
+    let id = job.status().id;
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(db.upgrade_lang(lang, job.as_ref()))
+    }));
+
+    // Make the upgraded language available right away
+    db.reload_langs();
+
+    let (state, error) = match result {
+        Ok(Ok(())) => (JobState::Succeeded, None),
+        Ok(Err(DbError::Cancelled)) => (JobState::Cancelled, None),
+        Ok(Err(e)) => (JobState::Failed, Some(e.to_string())),
+        Err(panic) => (JobState::Failed, Some(panic_message(panic))),
+    };
+
+    match &error {
+        Some(e) => log::error!(job = id, lang = lang.code.as_str(); "Upgrade failed: {}", e),
+        None if state == JobState::Cancelled => {
+            log::warn!(job = id, lang = lang.code.as_str(); "Upgrade cancelled")
+        },
+        None => {}
+    }
+
+    job.finish(state, error);
+    durations.observe(&job.status());
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("The upgrade panicked")
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+#[cfg(test)]
+impl Jobs {
+    /// Add a running job with no upgrade behind it, for tests to drive
+    pub fn add_running(&self, lang: &Language) -> Arc<Job> {
+        self.add_job(&mut self.jobs.lock().unwrap(), lang, "Starting...")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn lang(code: &str) -> Language {
+        Language::new(code, code)
+    }
+
+    #[test]
+    fn jobs_are_listed_in_order() {
+        let jobs = Jobs::default();
+
+        let first = jobs.add_running(&lang("pl"));
+        let second = jobs.add_running(&lang("de"));
+
+        assert_eq!(jobs.running("pl"), Some(first.status().id));
+        assert_eq!(jobs.running("de"), Some(second.status().id));
+        assert_eq!(jobs.running("fr"), None);
+
+        first.finish(JobState::Failed, Some(String::from("No dump")));
+
+        assert_eq!(jobs.running("pl"), None);
+
+        let statuses = jobs.list();
+
+        assert_eq!(statuses.iter().map(|status| status.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(statuses[0].state, JobState::Failed);
+        assert_eq!(statuses[0].error.as_deref(), Some("No dump"));
+        assert!(statuses[0].finished.is_some());
+        assert_eq!(statuses[1].state, JobState::Running);
+    }
+
+    #[test]
+    fn old_finished_jobs_are_forgotten() {
+        let jobs = Jobs::default();
+
+        let running = jobs.add_running(&lang("pl"));
+
+        for _ in 0..KEPT_FINISHED + 10 {
+            let job = jobs.add_running(&lang("de"));
+            job.finish(JobState::Succeeded, None);
+        }
+
+        jobs.add_running(&lang("fr"));
+
+        let statuses = jobs.list();
+
+        assert_eq!(statuses.len(), KEPT_FINISHED + 2);
+        // Running jobs are kept, however old
+        assert_eq!(statuses[0].id, running.status().id);
+        assert_eq!(statuses[1].id, 12);
+        assert!(jobs.get(11).is_none());
+    }
+
+    #[test]
+    fn progress_is_sent_to_subscribers() {
+        let jobs = Jobs::default();
+        let job = jobs.add_running(&lang("pl"));
+        let mut events = job.subscribe();
+
+        job.phase("Inserting entries...");
+        job.count(10, 20);
+        job.finish(JobState::Succeeded, None);
+
+        match events.try_recv().unwrap() {
+            JobEvent::Phase(status) => assert_eq!(status.phase, "Inserting entries..."),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        match events.try_recv().unwrap() {
+            JobEvent::Progress(status) => assert_eq!((status.done, status.total), (Some(10), Some(20))),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        match events.try_recv().unwrap() {
+            JobEvent::Finished(status) => assert_eq!(status.state, JobState::Succeeded),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn cancelling_is_seen_by_the_upgrade() {
+        let jobs = Jobs::default();
+        let job = jobs.add_running(&lang("pl"));
+
+        assert!(!job.cancelled());
+
+        jobs.cancel_all();
+
+        assert!(job.cancelled());
+    }
+
+    #[rocket::async_test]
+    async fn waits_for_running_jobs() {
+        let jobs = Jobs::default();
+
+        assert!(jobs.wait(Duration::ZERO).await);
+
+        let job = jobs.add_running(&lang("pl"));
+
+        assert!(!jobs.wait(Duration::from_millis(200)).await);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            job.finish(JobState::Cancelled, None);
+        });
+
+        assert!(jobs.wait(Duration::from_secs(5)).await);
+    }
+
+    #[test]
+    fn languages_being_removed_are_not_upgraded() {
+        let jobs = Jobs::default();
+        let dir = TempDir::new();
+        let db = WordDb::new(&testing::settings(&dir), "inflectived.db");
+
+        let removal = jobs.start_removal(&lang("pl")).ok().unwrap();
+
+        assert!(matches!(jobs.start_removal(&lang("pl")), Err(JobError::Removing)));
+        assert!(matches!(jobs.start_upgrade(&db, lang("pl")), Err(JobError::Removing)));
+        assert!(jobs.start_upgrades(&db, vec![lang("pl")]).is_empty());
+
+        drop(removal);
+
+        let upgrade = jobs.add_running(&lang("pl"));
+
+        assert!(matches!(jobs.start_removal(&lang("pl")), Err(JobError::AlreadyRunning(id)) if id == upgrade.status().id));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use rocket::{Request, State, Responder};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rusqlite::Row;
+use sha2::{Digest, Sha256};
+
+use crate::database::WordDb;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// An API key, as stored in the database
+#[derive(Debug, Clone)]
+pub struct ApiKeyInfo {
+    pub id: i64,
+    pub name: String,
+    /// Requests per second the bucket is refilled with
+    pub rate: f64,
+    /// Requests that can be made at once
+    pub burst: u32,
+    /// Requests allowed per UTC day
+    pub daily_quota: Option<u64>,
+}
+
+impl ApiKeyInfo {
+    pub fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id").unwrap(),
+            name: row.get("name").unwrap(),
+            rate: row.get("rate").unwrap(),
+            burst: row.get("burst").unwrap(),
+            daily_quota: row.get("daily_quota").unwrap(),
+        }
+    }
+}
+
+/// Generate a new random API key
+pub fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+
+    BASE64.encode(key)
+}
+
+/// Whether a key's limits let any request through in finite time. A NaN
+/// or infinite rate would make waits meaningless
+pub fn valid_limits(rate: f64, burst: u32) -> bool {
+    rate.is_finite() && rate > 0.0 && burst > 0
+}
+
+/// Keys are random enough that a fast hash is as good as a slow one
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+struct Usage {
+    tokens: f64,
+    refilled: Instant,
+    used_today: u64,
+}
+
+/// Usage of the keys used on a day
+#[derive(Default)]
+struct DailyUsage {
+    day: u64,
+    keys: HashMap<i64, Usage>,
+}
+
+/// API key settings and the usage of every key used today
+///
+/// Usage is kept in memory only, so restarting the daemon resets the daily
+/// quotas. It's forgotten when a new day starts, so removed keys don't stay
+/// around for long.
+pub struct ApiKeys {
+    pub required: bool,
+    usage: Mutex<DailyUsage>,
+}
+
+impl ApiKeys {
+    pub fn new(required: bool) -> Self {
+        Self {
+            required,
+            usage: Mutex::new(DailyUsage::default()),
+        }
+    }
+
+    /// Take a request out of a key's allowance, or return how many seconds
+    /// to wait before trying again
+    fn take(&self, key: &ApiKeyInfo) -> Result<(), u64> {
+        let mut usage = self.usage.lock().unwrap();
+
+        let now = Instant::now();
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let today = since_epoch / SECONDS_PER_DAY;
+
+        // Quotas start over, and buckets start full again, which lets keys
+        // emptying theirs right before midnight burst once more
+        if usage.day != today {
+            usage.day = today;
+            usage.keys.clear();
+        }
+
+        let usage = usage.keys.entry(key.id).or_insert(Usage {
+            tokens: key.burst as f64,
+            refilled: now,
+            used_today: 0,
+        });
+
+        if let Some(quota) = key.daily_quota {
+            if usage.used_today >= quota {
+                return Err(SECONDS_PER_DAY - since_epoch % SECONDS_PER_DAY);
+            }
+        }
+
+        let elapsed = now.duration_since(usage.refilled).as_secs_f64();
+        usage.tokens = (usage.tokens + elapsed * key.rate).min(key.burst as f64);
+        usage.refilled = now;
+
+        if usage.tokens < 1.0 {
+            return Err(((1.0 - usage.tokens) / key.rate).ceil() as u64);
+        }
+
+        usage.tokens -= 1.0;
+        usage.used_today += 1;
+
+        Ok(())
+    }
+}
+
+/// Request guard for lookup routes
+///
+/// When API keys are required, clients send theirs in the X-API-Key header.
+pub struct ApiKey;
+
+/// Seconds a rate-limited client should wait, for the 429 catcher
+struct RetryAfter(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let keys = req.guard::<&State<ApiKeys>>().await.unwrap();
+
+        if !keys.required {
+            return request::Outcome::Success(ApiKey);
+        }
+
+        let db = req.guard::<&State<WordDb>>().await.unwrap();
+
+        let key = match req.headers().get_one("X-API-Key") {
+            Some(key) => db.find_api_key(&hash_key(key)),
+            None => None
+        };
+
+        match key {
+            Some(key) => match keys.take(&key) {
+                Ok(()) => request::Outcome::Success(ApiKey),
+                Err(retry_after) => {
+                    req.local_cache(|| RetryAfter(Some(retry_after)));
+                    request::Outcome::Error((Status::TooManyRequests, ()))
+                }
+            },
+            None => request::Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    message: &'static str,
+    retry_after: Header<'static>,
+}
+
+#[rocket::catch(429)]
+pub fn too_many_requests(req: &Request) -> TooManyRequests {
+    let RetryAfter(retry_after) = req.local_cache(|| RetryAfter(None));
+
+    TooManyRequests {
+        message: "Rate limit or daily quota exceeded.",
+        retry_after: Header::new("Retry-After", retry_after.unwrap_or(1).to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn key(rate: f64, burst: u32, daily_quota: Option<u64>) -> ApiKeyInfo {
+        ApiKeyInfo { id: 1, name: String::from("test"), rate, burst, daily_quota }
+    }
+
+    #[test]
+    fn limits_must_be_positive_and_finite() {
+        assert!(valid_limits(0.5, 1));
+
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(!valid_limits(rate, 20), "{}", rate);
+        }
+
+        assert!(!valid_limits(10.0, 0));
+    }
+
+    #[test]
+    fn bursts_are_allowed_then_limited() {
+        let keys = ApiKeys::new(true);
+        let key = key(1.0, 3, None);
+
+        for _ in 0..3 {
+            assert_eq!(keys.take(&key), Ok(()));
+        }
+
+        assert_eq!(keys.take(&key), Err(1));
+    }
+
+    #[test]
+    fn waits_are_rounded_up() {
+        let keys = ApiKeys::new(true);
+        let key = key(0.25, 1, None);
+
+        assert_eq!(keys.take(&key), Ok(()));
+        assert_eq!(keys.take(&key), Err(4));
+    }
+
+    #[test]
+    fn keys_have_buckets_of_their_own() {
+        let keys = ApiKeys::new(true);
+        let first = key(1.0, 1, None);
+        let second = ApiKeyInfo { id: 2, ..first.clone() };
+
+        assert_eq!(keys.take(&first), Ok(()));
+        assert_eq!(keys.take(&second), Ok(()));
+        assert!(keys.take(&first).is_err());
+    }
+
+    #[test]
+    fn daily_quotas_last_until_midnight() {
+        let keys = ApiKeys::new(true);
+        let key = key(1000.0, 1000, Some(2));
+
+        assert_eq!(keys.take(&key), Ok(()));
+        assert_eq!(keys.take(&key), Ok(()));
+
+        let wait = keys.take(&key).unwrap_err();
+
+        assert!(wait > 0 && wait <= SECONDS_PER_DAY);
+    }
+
+    #[test]
+    fn usage_is_forgotten_on_a_new_day() {
+        let keys = ApiKeys::new(true);
+        let key = key(1.0, 1, Some(1));
+
+        assert_eq!(keys.take(&key), Ok(()));
+        assert!(keys.take(&key).is_err());
+
+        keys.usage.lock().unwrap().day -= 1;
+
+        assert_eq!(keys.take(&key), Ok(()));
+        assert_eq!(keys.usage.lock().unwrap().keys.len(), 1);
+    }
+
+    #[test]
+    fn lookups_need_a_key_within_its_limits() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::tracked(testing::rocket(&settings, &db, true)).unwrap();
+
+        let key = generate_key();
+        assert!(db.add_api_key("test", &hash_key(&key), 0.5, 1, None).ok().unwrap());
+
+        let lookup = |key: &str| {
+            client.get("/v1/langs/pol/words/kot")
+                  .header(Header::new("X-API-Key", String::from(key)))
+                  .dispatch()
+        };
+
+        assert_eq!(client.get("/v1/langs/pol/words/kot").dispatch().status(), Status::Unauthorized);
+        assert_eq!(lookup("wrong").status(), Status::Unauthorized);
+
+        // Not installed, but the key was accepted
+        assert_eq!(lookup(&key).status(), Status::NotFound);
+
+        let response = lookup(&key);
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("2"));
+    }
+
+    #[test]
+    fn key_names_are_unique() {
+        let dir = TempDir::new();
+        let db = WordDb::new(&testing::settings(&dir), "inflectived.db");
+
+        assert!(db.add_api_key("test", &hash_key("first"), 1.0, 1, None).ok().unwrap());
+        assert!(!db.add_api_key("test", &hash_key("second"), 2.0, 2, None).ok().unwrap());
+
+        let keys = db.list_api_keys();
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].rate, 1.0);
+        assert!(db.find_api_key(&hash_key("first")).is_some());
+        assert!(db.find_api_key(&hash_key("second")).is_none());
+    }
+}
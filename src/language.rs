@@ -2,14 +2,54 @@ use std::cmp::{PartialEq, PartialOrd, Ordering};
 
 use rusqlite::Row;
 use serde::Serialize;
+use utoipa::ToSchema;
 
 use crate::version::Version;
+use crate::migrations;
+use crate::iso639::{self, Codes};
 
-#[derive(Serialize, Debug, Clone)]
+/// How a language's database compares to the ones this daemon builds
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Built by this daemon or a newer one, or not installed
+    Current,
+    /// Built by an older daemon, so upgrading it may change its entries.
+    /// Its schema can be migrated meanwhile
+    Migratable,
+    /// Built by a daemon too old for it to be migrated, so it has to be
+    /// upgraded again
+    NeedsRebuild,
+}
+
+impl Status {
+    fn of(version: &Version) -> Self {
+        if migrations::needs_rebuild(version) {
+            Status::NeedsRebuild
+        } else if *version < Version::current() {
+            Status::Migratable
+        } else {
+            Status::Current
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
 pub struct Language {
-    pub code: String, // ISO 639-2
+    pub code: String, // Installed under, usually ISO 639-2/B or ISO 639-3
     pub name: String, // English name
-    pub version: Option<Version>
+    /// Every standard code of the language, any of which it can be asked
+    /// for by
+    pub codes: Codes,
+    /// Where the dump is downloaded from, if the catalog says
+    #[serde(skip)]
+    pub url: Option<String>,
+    pub version: Option<Version>,
+    pub upgraded: Option<i64>, // Unix time of the last upgrade
+    pub status: Status,
+    /// Whether an older daemon built it, so it should be upgraded again.
+    /// Same as its status not being current
+    pub outdated: bool,
 }
 
 impl Language {
@@ -17,20 +57,53 @@ impl Language {
         Self {
             code: String::from(code),
             name: String::from(name),
-            version: None
+            codes: Self::codes(code, name),
+            url: None,
+            version: None,
+            upgraded: None,
+            status: Status::Current,
+            outdated: false,
         }
     }
 
     pub fn from_row(row: &Row) -> Self {
+        let version = Version(row.get("major").unwrap(),
+                              row.get("minor").unwrap(),
+                              row.get("patch").unwrap());
+        let code: String = row.get("code").unwrap();
+        let name: String = row.get("name").unwrap();
+        let status = Status::of(&version);
+
         Self {
-            code: row.get(0).unwrap(),
-            name: row.get(1).unwrap(),
-            version: Some(Version(row.get(2).unwrap(),
-                                  row.get(3).unwrap(),
-                                  row.get(4).unwrap()))
+            codes: Self::codes(&code, &name),
+            code,
+            name,
+            url: None,
+            status,
+            outdated: status != Status::Current,
+            version: Some(version),
+            // Languages installed before this was tracked have no timestamp
+            upgraded: row.get("upgraded").ok()
         }
     }
 
+    fn codes(code: &str, name: &str) -> Codes {
+        iso639::by_code(code).or_else(|| iso639::by_name(name))
+                             .unwrap_or_default()
+    }
+
+    /// Whether `code` is safe to use in file names, which codes end up in
+    pub fn valid_code(code: &str) -> bool {
+        !code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Whether the language goes by `code`, be it its own code or any of its
+    /// standard ones
+    pub fn goes_by(&self, code: &str) -> bool {
+        self.code == code || self.codes.matches(code)
+    }
+
+    /// Languages installable out of the box, until the catalog is refreshed
     pub fn list_langs() -> Vec<Self> {
         // Keep this list sorted by name
         let langs = vec![
@@ -64,6 +137,77 @@ impl Ord for Language {
 
 impl PartialOrd for Language {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.name.cmp(&other.name))
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_follow_the_daemon_that_built_the_language() {
+        assert_eq!(Status::of(&Version::current()), Status::Current);
+        assert_eq!(Status::of(&Version(u32::MAX, 0, 0)), Status::Current);
+        assert_eq!(Status::of(&Version(0, 0, 1)), Status::NeedsRebuild);
+
+        // Only once a release after it is out
+        if migrations::REBUILD_BEFORE < Version::current() {
+            assert_eq!(Status::of(&migrations::REBUILD_BEFORE), Status::Migratable);
+        }
+    }
+
+    #[test]
+    fn languages_go_by_any_of_their_codes() {
+        let lang = Language::new("fre", "French");
+
+        for code in ["fre", "fr", "fra", "FRA"] {
+            assert!(lang.goes_by(code), "{}", code);
+        }
+
+        assert!(!lang.goes_by("ger"));
+        assert!(!lang.goes_by(""));
+    }
+
+    #[test]
+    fn codes_are_looked_up_by_name_without_a_standard_code() {
+        // Made-up codes, e.g. from the catalog
+        let lang = Language::new("tibetan", "Tibetan");
+
+        assert!(lang.goes_by("tibetan"));
+        assert!(lang.goes_by("bo"));
+
+        let lang = Language::new("klingon", "Klingon");
+
+        assert!(lang.goes_by("klingon"));
+        assert_eq!(lang.codes, Codes::default());
+    }
+
+    #[test]
+    fn codes_must_be_safe_in_file_names() {
+        assert!(Language::valid_code("pol"));
+        assert!(Language::valid_code("old-pl_1"));
+        assert!(!Language::valid_code(""));
+        assert!(!Language::valid_code(".."));
+        assert!(!Language::valid_code("a/b"));
+    }
+
+    #[test]
+    fn languages_built_by_older_daemons_are_outdated() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+
+        let lang = |major: u32| conn.query_row("SELECT 'pol' AS code, 'Polish' AS name, ? AS major, 0 AS minor, 0 AS patch",
+                                               [major],
+                                               |row| Ok(Language::from_row(row))).unwrap();
+
+        assert!(lang(0).outdated);
+        assert_eq!(lang(0).status, Status::NeedsRebuild);
+        assert!(!lang(u32::MAX).outdated);
+        assert!(!Language::new("pol", "Polish").outdated);
+    }
+
+    #[test]
+    fn statuses_are_serialized_in_snake_case() {
+        assert_eq!(serde_json::to_value(Status::NeedsRebuild).unwrap(), "needs_rebuild");
     }
 }
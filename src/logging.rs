@@ -0,0 +1,331 @@
+//! Structured logging, human-readable or as JSON lines
+//!
+//! Rocket logs through the same logger, so its own output follows the
+//! configured format too.
+
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use rocket::{Request, Response, Data};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest};
+use serde_json::{json, Map};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::settings::Settings;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format \"{}\", expected \"human\" or \"json\"", s))
+        }
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: Format,
+}
+
+/// Install the logger, as configured by the settings
+pub fn init(settings: &Settings) -> Result<(), String> {
+    let level = LevelFilter::from_str(&settings.log_level)
+                            .map_err(|_| format!("unknown log level \"{}\"", settings.log_level))?;
+    let format = settings.log_format.parse()?;
+
+    log::set_boxed_logger(Box::new(Logger { level, format })).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Other crates, Rocket included, are chatty at the info level, so
+        // they're kept to warnings unless debugging
+        let ours = metadata.target().starts_with("inflectived");
+
+        metadata.level() <= self.level
+            && (ours || metadata.level() <= Level::Warn || self.level >= LevelFilter::Debug)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Nowhere left to report a failure to
+        writeln!(io::stderr().lock(), "{}", self.format(record)).ok();
+    }
+
+    fn flush(&self) {
+        io::stderr().flush().ok();
+    }
+}
+
+impl Logger {
+    fn format(&self, record: &Record) -> String {
+        let time = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
+
+        let mut fields = Fields(Vec::new());
+        record.key_values().visit(&mut fields).ok();
+
+        match self.format {
+            Format::Human => {
+                let mut line = format!("{} {:<5} {}: {}", time, record.level(), record.target(), record.args());
+
+                for (key, value) in &fields.0 {
+                    match value {
+                        serde_json::Value::String(value) => line.push_str(&format!(" {}={:?}", key, value)),
+                        value => line.push_str(&format!(" {}={}", key, value))
+                    }
+                }
+
+                line
+            },
+            Format::Json => {
+                let mut object = Map::new();
+
+                object.insert(String::from("time"), json!(time));
+                object.insert(String::from("level"), json!(record.level().as_str().to_lowercase()));
+                object.insert(String::from("target"), json!(record.target()));
+                object.insert(String::from("message"), json!(record.args().to_string()));
+
+                for (key, value) in fields.0 {
+                    object.insert(key, value);
+                }
+
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+/// Key-value pairs of a record, with numbers and booleans kept as such.
+/// Pairs whose value is `None` are left out
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = Field(serde_json::Value::Null);
+        value.visit(&mut field)?;
+
+        if !field.0.is_null() {
+            self.0.push((key.to_string(), field.0));
+        }
+
+        Ok(())
+    }
+}
+
+struct Field(serde_json::Value);
+
+impl<'v> VisitValue<'v> for Field {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.0 = json!(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = json!(value);
+        Ok(())
+    }
+}
+
+/// Details of a lookup, filled in by the route handling it and logged along
+/// with the request
+///
+/// Also a request guard, so routes can get hold of it.
+#[derive(Default)]
+pub struct RequestLog {
+    lang: Mutex<Option<String>>,
+    word: Mutex<Option<String>>,
+    results: Mutex<Option<usize>>,
+}
+
+impl RequestLog {
+    pub fn lang(&self, lang: &str) {
+        *self.lang.lock().unwrap() = Some(String::from(lang));
+    }
+
+    pub fn word(&self, word: &str) {
+        *self.word.lock().unwrap() = Some(String::from(word));
+    }
+
+    pub fn results(&self, results: usize) {
+        *self.results.lock().unwrap() = Some(results);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestLog {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(req.local_cache(RequestLog::default))
+    }
+}
+
+/// Logs every request once it's answered
+pub struct RequestLogger {
+    /// Log lookup words as `<redacted>`
+    pub redact_words: bool,
+}
+
+/// When the request came in, kept in its local cache
+struct Started(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logging",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| Started(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Started(started) = req.local_cache(|| Started(None));
+        let latency_ms = started.map(|started| started.elapsed().as_secs_f64() * 1000.0);
+
+        // The route's pattern rather than the path, which has the word in it
+        let route = match req.route() {
+            Some(route) => route.uri.to_string(),
+            None => String::from("unmatched")
+        };
+
+        let details = req.local_cache(RequestLog::default);
+        let lang = details.lang.lock().unwrap().clone();
+        let word = match details.word.lock().unwrap().clone() {
+            Some(_) if self.redact_words => Some(String::from("<redacted>")),
+            word => word
+        };
+        let results = *details.results.lock().unwrap();
+
+        log::info!(method = req.method().as_str(),
+                   route = route.as_str(),
+                   status = res.status().code,
+                   latency_ms = latency_ms,
+                   lang = lang.as_deref(),
+                   word = word.as_deref(),
+                   results = results;
+                   "request");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::kv::ToValue;
+
+    use super::*;
+
+    fn record<'a>(level: Level, target: &'a str, args: std::fmt::Arguments<'a>) -> Record<'a> {
+        Record::builder().level(level).target(target).args(args).build()
+    }
+
+    #[test]
+    fn formats() {
+        assert!(Format::from_str("human") == Ok(Format::Human));
+        assert!(Format::from_str("json") == Ok(Format::Json));
+        assert!(Format::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn other_crates_are_kept_to_warnings() {
+        let logger = Logger { level: LevelFilter::Info, format: Format::Human };
+        let enabled = |level, target| logger.enabled(&Metadata::builder().level(level).target(target).build());
+
+        assert!(enabled(Level::Info, "inflectived::jobs"));
+        assert!(!enabled(Level::Debug, "inflectived::jobs"));
+        assert!(!enabled(Level::Info, "rocket::server"));
+        assert!(enabled(Level::Warn, "rocket::server"));
+
+        let logger = Logger { level: LevelFilter::Debug, format: Format::Human };
+
+        assert!(logger.enabled(&Metadata::builder().level(Level::Info).target("rocket::server").build()));
+    }
+
+    #[test]
+    fn human_lines() {
+        let logger = Logger { level: LevelFilter::Info, format: Format::Human };
+        let fields = [("lang", "pol".to_value()), ("status", 200.to_value()), ("word", None::<&str>.to_value())];
+        let source: &[(&str, Value)] = &fields;
+
+        let line = logger.format(&Record::builder().level(Level::Info)
+                                                   .target("inflectived::logging")
+                                                   .args(format_args!("request"))
+                                                   .key_values(&source)
+                                                   .build());
+
+        assert!(line.ends_with(r#" INFO  inflectived::logging: request lang="pol" status=200"#), "{}", line);
+    }
+
+    #[test]
+    fn json_lines() {
+        let logger = Logger { level: LevelFilter::Info, format: Format::Json };
+        let fields = [("lang", "pol".to_value()), ("seconds", 1.5.to_value()), ("word", None::<&str>.to_value())];
+        let source: &[(&str, Value)] = &fields;
+
+        let line = logger.format(&Record::builder().level(Level::Warn)
+                                                   .target("inflectived::database")
+                                                   .args(format_args!("Upgrade finished"))
+                                                   .key_values(&source)
+                                                   .build());
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["target"], "inflectived::database");
+        assert_eq!(json["message"], "Upgrade finished");
+        assert_eq!(json["lang"], "pol");
+        assert_eq!(json["seconds"], 1.5);
+        assert!(json.get("word").is_none());
+        assert!(OffsetDateTime::parse(json["time"].as_str().unwrap(), &Rfc3339).is_ok());
+    }
+
+    #[test]
+    fn messages_without_fields() {
+        let logger = Logger { level: LevelFilter::Info, format: Format::Human };
+        let line = logger.format(&record(Level::Error, "inflectived", format_args!("Couldn't bind")));
+
+        assert!(line.ends_with(" ERROR inflectived: Couldn't bind"), "{}", line);
+    }
+}
@@ -0,0 +1,65 @@
+use rocket::{Request, State};
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{self, FromRequest};
+
+use crate::cache::{Cached, CacheControl, Preconditions, Validators};
+use crate::database::{WordDb, DbError};
+use crate::keys::ApiKey;
+use crate::language::Language;
+use crate::logging::RequestLog;
+use crate::metrics::Metrics;
+
+/// Request guard for lookup routes, bundling what they need
+///
+/// The client's API key is checked first, so unauthorized or rate-limited
+/// requests are turned away before anything else.
+pub struct Lookup<'r> {
+    pub db: &'r WordDb,
+    pub metrics: &'r Metrics,
+    pub log: &'r RequestLog,
+    cache_control: &'r CacheControl,
+    preconditions: Preconditions,
+}
+
+impl Lookup<'_> {
+    /// Find an installed language by any of its codes, logging it
+    pub fn installed_lang(&self, code: &str) -> Option<Language> {
+        let installed = self.db.get_installed_lang(code);
+
+        self.log.lang(installed.as_ref().map_or(code, |lang| &lang.code));
+
+        installed
+    }
+
+    /// Log a failed lookup, answering with a 500
+    pub fn failed(&self, lang: &Language, e: DbError) -> Status {
+        log::error!(lang = lang.code.as_str(); "Lookup failed: {}", e);
+
+        Status::InternalServerError
+    }
+
+    /// Respond with the body built by `make_body`, or with a 304 if the
+    /// client's copy of the language's data is fresh
+    pub fn cached<R, F>(&self, lang: &Language, make_body: F) -> Cached<R>
+        where F: FnOnce() -> R {
+        Cached::new(make_body, Validators::for_lang(lang), &self.preconditions, self.cache_control)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Lookup<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        try_outcome!(req.guard::<ApiKey>().await);
+
+        request::Outcome::Success(Self {
+            db: try_outcome!(req.guard::<&State<WordDb>>().await),
+            metrics: try_outcome!(req.guard::<&State<Metrics>>().await),
+            log: try_outcome!(req.guard::<&RequestLog>().await),
+            cache_control: try_outcome!(req.guard::<&State<CacheControl>>().await),
+            preconditions: try_outcome!(req.guard::<Preconditions>().await),
+        })
+    }
+}
@@ -2,11 +2,16 @@
 
 use std::process::exit;
 use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::time::Duration;
+use std::str::FromStr;
 
 //mod database;
-use rocket::routes;
+use rocket::{routes, catchers};
 use rocket::fs::FileServer;
-use clap::{App, AppSettings, Arg, SubCommand};
+use rocket::fairing::AdHoc;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 //use database::WordDb;
 mod database;
@@ -15,12 +20,37 @@ mod entry;
 mod views;
 mod version;
 mod util;
+mod cache;
+mod v1;
+mod projection;
+mod auth;
+mod jobs;
+mod admin;
+mod keys;
+mod lookup;
+mod settings;
+mod pool;
+mod metrics;
+mod health;
+mod logging;
+mod systemd;
+mod snapshot;
+mod maintenance;
+mod migrations;
+mod catalog;
+mod iso639;
+#[cfg(test)]
+mod testing;
 
-use database::{WordDb, DbError};
-
-const DB_DIR: &str = "/usr/share/inflectived";
-const CACHE_DIR: &str = "/var/cache/inflectived";
-const FRONTEND_DIR: &str = "/opt/inflectived";
+use database::{WordDb, DbError, NoProgress};
+use cache::CacheControl;
+use jobs::Jobs;
+use keys::ApiKeys;
+use settings::Settings;
+use metrics::{Metrics, RequestTimer};
+use logging::RequestLogger;
+use snapshot::SnapshotError;
+use migrations::Outcome;
 
 const MAJOR: i32 = 0;
 const MINOR: i32 = 1;
@@ -32,22 +62,135 @@ async fn main() {
         .version("0.1")
         .author("Augusto Gunsch <augustogunsch@tutanota.com>")
         .about("inflective daemon")
+        .args(&[
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory to keep language databases in")
+                .takes_value(true),
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .help("Directory to cache downloaded dumps in")
+                .takes_value(true),
+            Arg::with_name("frontend-dir")
+                .long("frontend-dir")
+                .value_name("DIR")
+                .help("Directory the web frontend is installed in")
+                .takes_value(true),
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Least severe level to log: off, error, warn, info, debug or trace")
+                .takes_value(true),
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Format of log lines: human or json")
+                .takes_value(true),
+        ])
         .subcommands(vec![
             SubCommand::with_name("upgrade")
                 .about("Upgrade or install a language database")
                 .arg(
                     Arg::with_name("LANG")
-                        .required(true)
+                        .required_unless("all-outdated")
+                        .conflicts_with("all-outdated")
                         .index(1)
                         .help("Language database to upgrade"),
+                )
+                .arg(
+                    Arg::with_name("all-outdated")
+                        .long("all-outdated")
+                        .help("Upgrade every language built by an older version"),
+                ),
+            SubCommand::with_name("remove")
+                .about("Remove a language database")
+                .arg(
+                    Arg::with_name("LANG")
+                        .required(true)
+                        .index(1)
+                        .help("Language database to remove"),
+                )
+                .arg(
+                    Arg::with_name("cache")
+                        .short("c")
+                        .long("cache")
+                        .help("Also remove the cached dump of the language"),
+                ),
+            SubCommand::with_name("export")
+                .about("Export a language database as newline-delimited JSON")
+                .arg(
+                    Arg::with_name("LANG")
+                        .required(true)
+                        .index(1)
+                        .help("Language database to export"),
+                )
+                .arg(
+                    Arg::with_name("generated")
+                        .short("g")
+                        .long("generated")
+                        .help("Include automatically generated \"form-of\" entries"),
+                ),
+            SubCommand::with_name("pack")
+                .about("Pack an installed language into an archive, to be unpacked elsewhere")
+                .arg(
+                    Arg::with_name("LANG")
+                        .required(true)
+                        .index(1)
+                        .help("Language database to pack"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write the archive to (\"-\" for stdout) [default: LANG.tar.gz]")
+                        .takes_value(true),
+                ),
+            SubCommand::with_name("unpack")
+                .about("Install a language from an archive made by pack")
+                .arg(
+                    Arg::with_name("FILE")
+                        .required(true)
+                        .index(1)
+                        .help("Archive to unpack (\"-\" for stdin)"),
                 ),
+            SubCommand::with_name("migrate")
+                .about("Migrate language databases built by older versions"),
+            SubCommand::with_name("maintain")
+                .about("Check the databases for problems, then analyze and vacuum them"),
             SubCommand::with_name("run").about("Run the daemon").arg(
+                Arg::with_name("address")
+                    .short("a")
+                    .long("address")
+                    .value_name("ADDRESS")
+                    .help("Address to bind the server to")
+                    .takes_value(true),
+            ).arg(
                 Arg::with_name("port")
                     .short("p")
                     .long("port")
                     .value_name("PORT")
                     .help("Port to run the server on")
                     .takes_value(true),
+            ).arg(
+                Arg::with_name("cache-control")
+                    .long("cache-control")
+                    .value_name("DIRECTIVES")
+                    .help("Cache-Control header sent with lookups (empty to omit) [default: public, max-age=3600, or private when API keys are required]")
+                    .takes_value(true),
+            ).arg(
+                Arg::with_name("require-api-key")
+                    .long("require-api-key")
+                    .help("Require an API key for lookups"),
+            ).arg(
+                Arg::with_name("reload-interval")
+                    .long("reload-interval")
+                    .value_name("SECONDS")
+                    .help("How often to check for languages upgraded by other processes (0 to never)")
+                    .default_value("60")
+                    .takes_value(true),
             ),
             SubCommand::with_name("list")
                 .about("List language databases")
@@ -56,51 +199,708 @@ async fn main() {
                         .short("i")
                         .long("installed")
                         .help("List only installed databases"),
+                )
+                .arg(
+                    Arg::with_name("outdated")
+                        .long("outdated")
+                        .conflicts_with("installed")
+                        .help("List only installed databases built by an older version"),
+                ),
+            SubCommand::with_name("passwd")
+                .about("Set admin password for remote management")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Replace the current password without asking for it"),
+                ),
+            SubCommand::with_name("config")
+                .about("Inspect the configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("show").about("Print the effective configuration")),
+            SubCommand::with_name("catalog")
+                .about("Manage the catalog of installable languages")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("refresh")
+                        .about("Refresh the catalog from the index of the dumps")
+                        .arg(
+                            Arg::with_name("index")
+                                .long("index")
+                                .value_name("URL")
+                                .help("Index to refresh from, e.g. a mirror's, instead of the configured one")
+                                .takes_value(true),
+                        ),
                 ),
-            SubCommand::with_name("passwd").about("Set admin password for remote management"),
+            SubCommand::with_name("keys")
+                .about("Manage API keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommands(vec![
+                    SubCommand::with_name("add")
+                        .about("Add an API key")
+                        .arg(
+                            Arg::with_name("NAME")
+                                .required(true)
+                                .index(1)
+                                .help("Name to manage the key by"),
+                        )
+                        .arg(
+                            Arg::with_name("rate")
+                                .long("rate")
+                                .value_name("REQUESTS")
+                                .help("Requests per second allowed in the long run")
+                                .default_value("10")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("burst")
+                                .long("burst")
+                                .value_name("REQUESTS")
+                                .help("Requests allowed at once")
+                                .default_value("20")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("quota")
+                                .long("quota")
+                                .value_name("REQUESTS")
+                                .help("Requests allowed per day (unlimited if not given)")
+                                .takes_value(true),
+                        ),
+                    SubCommand::with_name("list").about("List API keys"),
+                    SubCommand::with_name("remove")
+                        .about("Remove an API key")
+                        .arg(
+                            Arg::with_name("NAME")
+                                .required(true)
+                                .index(1)
+                                .help("Name of the key to remove"),
+                        ),
+                ]),
         ])
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
-    let mut db = WordDb::new("inflectived.db");
+    let mut overrides = Vec::new();
+
+    for arg in &["data-dir", "cache-dir", "frontend-dir", "log-level", "log-format"] {
+        if let Some(value) = matches.value_of(arg) {
+            overrides.push((arg.replace('-', "_"), value));
+        }
+    }
+
+    if let ("run", Some(matches)) = matches.subcommand() {
+        for arg in &["address", "port"] {
+            if let Some(value) = matches.value_of(arg) {
+                overrides.push((String::from(*arg), value));
+            }
+        }
+    }
+
+    let overrides: Vec<(&str, &str)> = overrides.iter()
+                                                .map(|(key, value)| (key.as_str(), *value))
+                                                .collect();
+
+    let settings = match Settings::load(&overrides) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            exit(1);
+        }
+    };
+
+    if let ("config", _) = matches.subcommand() {
+        println!("{}", settings);
+        return;
+    }
+
+    if let Err(e) = logging::init(&settings) {
+        eprintln!("Invalid configuration: {}", e);
+        exit(1);
+    }
+
+    let db = open_db(&settings);
 
     match matches.subcommand() {
         ("upgrade", matches) => {
-            let lang = db.get_lang(matches.unwrap().value_of("LANG").unwrap());
+            let matches = matches.unwrap();
+
+            let langs = if matches.is_present("all-outdated") {
+                let outdated = db.outdated_langs();
+
+                if outdated.is_empty() {
+                    println!("All languages are up to date.");
+                    return;
+                }
+
+                outdated
+            } else {
+                let Some(lang) = db.get_lang(matches.value_of("LANG").unwrap()) else {
+                    eprintln!("The requested language is not available.");
+                    eprintln!("Available languages:");
+                    eprint!("{}", db.list_available());
+                    exit(1);
+                };
+
+                vec![lang]
+            };
+
+            for lang in langs {
+                if matches.is_present("all-outdated") {
+                    println!("Upgrading {} ({})...", lang.name, lang.code);
+                }
 
-            if let None = lang {
-                eprintln!("The requested language is not available.");
-                eprintln!("Available languages:");
-                eprint!("{}", db.list_available());
+                if let Err(e) = db.upgrade_lang(&lang, &NoProgress).await {
+                    match e {
+                        DbError::Cancelled => {
+                            eprintln!("Upgrade cancelled.");
+                            exit(1);
+                        },
+                        e => exit_db_error(e)
+                    }
+                }
+            }
+        },
+        ("list", matches) => {
+            let matches = matches.unwrap();
+
+            if matches.is_present("outdated") {
+                print!("{}", db.list_outdated());
+            } else if matches.is_present("installed") {
+                print!("{}", db.list_installed());
+            } else {
+                print!("{}", db.list_available());
+            }
+        },
+        ("remove", matches) => {
+            let matches = matches.unwrap();
+            let Some(lang) = db.get_installed_lang(matches.value_of("LANG").unwrap()) else {
+                eprintln!("The requested language is not installed.");
+                eprintln!("Installed languages:");
+                eprint!("{}", db.list_installed());
                 exit(1);
+            };
+
+            if let Err(e) = db.remove_lang(&lang, matches.is_present("cache")) {
+                exit_db_error(e);
             }
+        },
+        ("export", matches) => {
+            let matches = matches.unwrap();
+            let Some(lang) = db.get_installed_lang(matches.value_of("LANG").unwrap()) else {
+                eprintln!("The requested language is not installed.");
+                eprintln!("Installed languages:");
+                eprint!("{}", db.list_installed());
+                exit(1);
+            };
 
-            if let Err(e) = db.upgrade_lang(&lang.unwrap()).await {
-                match e {
-                    DbError::AccessDenied => {
-                        eprintln!("Permission denied. Please run as root.");
-                        exit(1);
-                    }
+            let conn = match db.read_lang(&lang) {
+                Ok(conn) => conn,
+                Err(e) => exit_db_error(e)
+            };
+
+            let stdout = io::stdout();
+            let writer = BufWriter::new(stdout.lock());
+
+            if let Err(e) = WordDb::export_entries(&conn,
+                                                   matches.is_present("generated"),
+                                                   writer) {
+                // Stop quietly when piped into e.g. head
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    eprintln!("{}", e);
+                    exit(1);
                 }
             }
         },
-        ("run", _matches) => {
-            let figment = rocket::Config::figment()
-                                         .merge(("address", "0.0.0.0"));
+        ("pack", matches) => {
+            let matches = matches.unwrap();
+            let Some(lang) = db.get_installed_lang(matches.value_of("LANG").unwrap()) else {
+                eprintln!("The requested language is not installed.");
+                eprintln!("Installed languages:");
+                eprint!("{}", db.list_installed());
+                exit(1);
+            };
+
+            let output = match matches.value_of("output") {
+                Some(output) => String::from(output),
+                None => format!("{}.tar.gz", lang.code)
+            };
+
+            let result = if output == "-" {
+                snapshot::pack(&db, &settings, &lang, io::stdout().lock())
+            } else {
+                let result = match File::create(&output) {
+                    Ok(file) => snapshot::pack(&db, &settings, &lang, BufWriter::new(file)),
+                    Err(e) => Err(SnapshotError::from(e))
+                };
+
+                if result.is_err() {
+                    fs::remove_file(&output).ok();
+                }
+
+                result
+            };
+
+            match result {
+                Ok(manifest) => eprintln!("Packed {} ({} entries, {} generated) into {}.",
+                                          manifest.name, manifest.entries, manifest.generated, output),
+                Err(SnapshotError::Db(DbError::AccessDenied)) => exit_access_denied(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        },
+        ("unpack", matches) => {
+            let file = matches.unwrap().value_of("FILE").unwrap();
+
+            let result = if file == "-" {
+                snapshot::unpack(&db, &settings, io::stdin().lock())
+            } else {
+                match File::open(file) {
+                    Ok(file) => snapshot::unpack(&db, &settings, BufReader::new(file)),
+                    Err(e) => Err(SnapshotError::from(e))
+                }
+            };
+
+            match result {
+                Ok(manifest) => eprintln!("Unpacked {} ({} entries, {} generated), built by inflectived {}.",
+                                          manifest.name, manifest.entries, manifest.generated, manifest.version),
+                Err(SnapshotError::Db(DbError::AccessDenied)) => exit_access_denied(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        },
+        ("migrate", _) => {
+            for (lang, outcome) in migrations::migrate_all(&db) {
+                match outcome {
+                    Ok(Outcome::UpToDate) => println!("{} ({}): up to date", lang.name, lang.code),
+                    Ok(Outcome::Migrated(applied)) => {
+                        println!("{} ({}): migrated", lang.name, lang.code);
+
+                        for description in applied {
+                            println!("    {}", description);
+                        }
+                    },
+                    Ok(Outcome::NeedsRebuild) => println!("{} ({}): too old to migrate, run \"inflectived upgrade {}\"",
+                                                          lang.name, lang.code, lang.code),
+                    Err(e) => exit_db_error(e)
+                }
+            }
+        },
+        ("maintain", _) => {
+            let report = maintenance::maintain(&db);
+
+            print!("{}", report);
+
+            if !report.healthy() {
+                exit(1);
+            }
+        },
+        ("run", matches) => {
+            let matches = matches.unwrap();
+            let cache_control = match matches.value_of("cache-control") {
+                Some(cache_control) => CacheControl(String::from(cache_control)),
+                None => CacheControl::default_for(matches.is_present("require-api-key"))
+            };
+            let reload_interval: u64 = parse_arg(matches, "reload-interval");
+
+            for (lang, outcome) in migrations::migrate_all(&db) {
+                match outcome {
+                    Ok(Outcome::UpToDate) => {},
+                    Ok(Outcome::Migrated(applied)) => log::info!(lang = lang.code.as_str(),
+                                                                 migrations = applied.len();
+                                                                 "Migrated language"),
+                    Ok(Outcome::NeedsRebuild) => log::warn!(lang = lang.code.as_str();
+                                                            "Language is too old to migrate, it has to be upgraded again"),
+                    Err(e) => log::warn!(lang = lang.code.as_str(); "Couldn't migrate language: {}", e)
+                }
+            }
+
+            if reload_interval > 0 {
+                let db = db.clone();
+
+                rocket::tokio::spawn(async move {
+                    let mut interval = rocket::tokio::time::interval(Duration::from_secs(reload_interval));
+
+                    loop {
+                        interval.tick().await;
+
+                        let db = db.clone();
+                        if rocket::tokio::task::spawn_blocking(move || {
+                            db.reload_catalog();
+                            db.reload_langs()
+                        }).await.unwrap() {
+                            log::info!("Installed languages changed, reloaded them");
+                        }
+                    }
+                });
+            }
+
+            let activated = systemd::listener();
+
+            // In-flight requests get this long to finish on shutdown
+            let figment = rocket::Config::figment().merge(("address", &settings.address))
+                                                   .merge(("port", settings.port))
+                                                   .merge(("shutdown.grace", settings.shutdown_grace));
 
             let mut app = rocket::custom(figment)
                                  .manage(db)
-                                 .mount("/", routes![views::get_entries,
-                                                     views::get_entries_like,
-                                                     views::get_langs,
-                                                     views::frontend]);
+                                 .manage(settings.clone())
+                                 .manage(cache_control)
+                                 .manage(Jobs::default())
+                                 .manage(auth::VerifiedPassword::default())
+                                 .manage(ApiKeys::new(matches.is_present("require-api-key")))
+                                 .manage(Metrics::default())
+                                 .attach(RequestTimer)
+                                 .attach(RequestLogger { redact_words: settings.redact_words })
+                                 .register("/", catchers![keys::too_many_requests])
+                                 .register("/admin", catchers![auth::unauthorized]);
+
+            for (base, routes) in mounts() {
+                app = app.mount(base, routes);
+            }
+
+            if let Some(user) = settings.user.clone() {
+                let (data_dir, cache_dir) = (settings.data_dir.clone(), settings.cache_dir.clone());
+
+                // Binding to privileged ports needs root, so wait until the
+                // server is listening
+                app = app.attach(AdHoc::on_liftoff("Drop privileges", move |_| Box::pin(async move {
+                    if nix::unistd::geteuid().is_root() {
+                        if let Err(e) = util::drop_privileges(&user, &[&data_dir, &cache_dir]) {
+                            log::error!(user = user.as_str(); "Couldn't switch to user: {}", e);
+                            exit(1);
+                        }
+                    }
+                })));
+            }
+
+            if let Ok(true) = Path::new(&settings.frontend_dir).try_exists() {
+                app = app.mount("/static", FileServer::from(&settings.frontend_dir));
+            }
+
+            if settings.rebuild_outdated {
+                // After dropping privileges, so the rebuilt databases don't
+                // end up owned by root
+                app = app.attach(AdHoc::on_liftoff("Rebuild outdated languages", |rocket| Box::pin(async move {
+                    let db = rocket.state::<WordDb>().unwrap();
+                    let outdated = db.outdated_langs();
+
+                    if !outdated.is_empty() {
+                        log::info!(langs = outdated.len(); "Rebuilding outdated languages");
+                        rocket.state::<Jobs>().unwrap().start_upgrades(db, outdated);
+                    }
+                })));
+            }
+
+            let app = app.attach(AdHoc::on_liftoff("Readiness", |_| Box::pin(async {
+                             systemd::notify("READY=1");
+                         })))
+                         .attach(AdHoc::on_shutdown("Cancel upgrades", |rocket| Box::pin(async move {
+                             stop_upgrades(rocket.state::<Jobs>().unwrap());
+                         })));
+
+            match activated {
+                Some(listener) => {
+                    let client = systemd::serve(app, listener).await.unwrap();
+                    let jobs = client.rocket().state::<Jobs>().unwrap();
+
+                    // Left to us, as Rocket doesn't run shutdown fairings
+                    // when it didn't bind the socket itself
+                    stop_upgrades(jobs);
+                    wait_for_upgrades(jobs).await;
+                },
+                None => {
+                    let rocket = app.launch().await.unwrap();
+
+                    wait_for_upgrades(rocket.state::<Jobs>().unwrap()).await;
+                }
+            }
+        },
+        ("passwd", matches) => {
+            if let Some(hash) = db.get_admin_password_hash() {
+                if !matches.unwrap().is_present("force") {
+                    let current = rpassword::prompt_password("Current password: ").unwrap();
+
+                    if !auth::verify_password(&current, &hash) {
+                        eprintln!("Wrong password.");
+                        exit(1);
+                    }
+                }
+            }
+
+            let password = rpassword::prompt_password("New password: ").unwrap();
+
+            if password.is_empty() {
+                eprintln!("The password can't be empty.");
+                exit(1);
+            }
+
+            if password != rpassword::prompt_password("Retype new password: ").unwrap() {
+                eprintln!("Passwords don't match.");
+                exit(1);
+            }
 
-            if let Ok(_) = Path::new(FRONTEND_DIR).try_exists() {
-                app = app.mount("/static", FileServer::from(FRONTEND_DIR));
+            if let Err(e) = db.set_admin_password_hash(&auth::hash_password(&password)) {
+                exit_db_error(e);
             }
 
-            app.launch().await.unwrap();
+            println!("Password updated.");
+        },
+        ("catalog", matches) => {
+            if let ("refresh", matches) = matches.unwrap().subcommand() {
+                let index = matches.unwrap().value_of("index").unwrap_or(&settings.index_url);
+
+                let langs = match catalog::fetch_index(&settings, index).await {
+                    Ok(langs) => langs,
+                    Err(e) => {
+                        eprintln!("Couldn't refresh the catalog: {}", e);
+                        exit(1);
+                    }
+                };
+
+                if let Err(e) = catalog::save(&settings, &langs) {
+                    if e.kind() == io::ErrorKind::PermissionDenied {
+                        exit_access_denied();
+                    }
+
+                    eprintln!("Couldn't save the catalog: {}", e);
+                    exit(1);
+                }
+
+                println!("{} languages available.", langs.len());
+            }
+        },
+        ("keys", matches) => match matches.unwrap().subcommand() {
+            ("add", matches) => {
+                let matches = matches.unwrap();
+
+                let rate: f64 = parse_arg(matches, "rate");
+                let burst: u32 = parse_arg(matches, "burst");
+                let quota: Option<u64> = matches.value_of("quota").map(|_| parse_arg(matches, "quota"));
+
+                if !keys::valid_limits(rate, burst) {
+                    eprintln!("The rate must be a finite number and, like the burst, greater than zero.");
+                    exit(1);
+                }
+
+                let key = keys::generate_key();
+
+                let name = matches.value_of("NAME").unwrap();
+
+                match db.add_api_key(name, &keys::hash_key(&key), rate, burst, quota) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        eprintln!("A key named {} already exists. Remove it first to replace it.", name);
+                        exit(1);
+                    },
+                    Err(e) => exit_db_error(e)
+                }
+
+                println!("{}", key);
+                eprintln!("Keep this key safe, it can't be shown again.");
+            },
+            ("list", _) => {
+                for key in db.list_api_keys() {
+                    let quota = match key.daily_quota {
+                        Some(quota) => format!("{}/day", quota),
+                        None => String::from("no quota")
+                    };
+
+                    println!(" - {} ({}/s, bursts of {}, {})", key.name, key.rate, key.burst, quota);
+                }
+            },
+            ("remove", matches) => {
+                let name = matches.unwrap().value_of("NAME").unwrap();
+
+                match db.remove_api_key(name) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        eprintln!("No such key: {}", name);
+                        exit(1);
+                    },
+                    Err(e) => exit_db_error(e)
+                }
+            },
+            _ => {}
         },
         _ => {}
     }
 }
+
+/// Routes of the daemon, along with where they're mounted
+fn mounts() -> Vec<(&'static str, Vec<rocket::Route>)> {
+    vec![
+        ("/", routes![views::get_entries,
+                      views::get_entries_like,
+                      views::export,
+                      views::get_langs,
+                      views::openapi,
+                      metrics::get_metrics,
+                      health::healthz,
+                      health::readyz,
+                      views::frontend]),
+        ("/v1", routes![v1::get_entries,
+                        v1::get_words_like,
+                        v1::get_langs]),
+        ("/admin", routes![admin::upgrade_lang,
+                           admin::remove_lang,
+                           admin::reload_langs,
+                           admin::get_jobs,
+                           admin::get_job,
+                           admin::get_job_events]),
+    ]
+}
+
+/// Open the database, bringing older layouts up to date first, so every
+/// command finds languages in files of their own
+fn open_db(settings: &Settings) -> WordDb {
+    let db = WordDb::new(settings, "inflectived.db");
+    db.recover();
+
+    db
+}
+
+/// Cancel running upgrades on shutdown. They roll back, so the database is
+/// left as it was before they started
+fn stop_upgrades(jobs: &Jobs) {
+    systemd::notify("STOPPING=1");
+    jobs.cancel_all();
+}
+
+/// Let cancelled upgrades finish rolling back before exiting
+async fn wait_for_upgrades(jobs: &Jobs) {
+    if !jobs.wait(Duration::from_secs(30)).await {
+        log::warn!("Upgrades are still running, exiting anyway");
+    }
+}
+
+fn exit_access_denied() -> ! {
+    eprintln!("Permission denied. Please run as root or set writable data and cache directories.");
+    exit(1);
+}
+
+fn exit_db_error(e: DbError) -> ! {
+    match e {
+        DbError::AccessDenied => exit_access_denied(),
+        e => {
+            eprintln!("{}.", e);
+            exit(1);
+        }
+    }
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> T {
+    match matches.value_of(name).unwrap().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            eprintln!("Invalid value for --{}: {}", name, matches.value_of(name).unwrap());
+            exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use utoipa::OpenApi;
+
+    use super::*;
+    use crate::testing::{self, TempDir, KOT};
+
+    /// A route's path as written in OpenAPI, e.g. `/langs/{lang}`
+    fn openapi_path(path: &str) -> (String, BTreeSet<String>) {
+        let mut params = BTreeSet::new();
+
+        let segments: Vec<String> = path.split('/').map(|segment| {
+            match segment.strip_prefix('<').and_then(|segment| segment.strip_suffix('>')) {
+                Some(param) => {
+                    params.insert(String::from(param));
+                    format!("{{{}}}", param)
+                },
+                None => String::from(segment)
+            }
+        }).collect();
+
+        (segments.join("/"), params)
+    }
+
+    #[test]
+    fn api_doc_describes_every_route() {
+        let doc = serde_json::to_value(views::ApiDoc::openapi()).unwrap();
+        let rocket = mounts().into_iter()
+                             .fold(rocket::build(), |rocket, (base, routes)| rocket.mount(base, routes));
+
+        for route in rocket.routes() {
+            // The web frontend isn't part of the API
+            if route.name.as_deref() == Some("frontend") {
+                continue;
+            }
+
+            let (path, params) = openapi_path(route.uri.path());
+            let method = route.method.as_str().to_lowercase();
+
+            let operation = &doc["paths"][&path][&method];
+            assert!(operation.is_object(), "{} {} is not described", method, path);
+
+            let described: BTreeSet<String> = operation["parameters"].as_array()
+                                                                     .into_iter()
+                                                                     .flatten()
+                                                                     .filter(|param| param["in"] == "path")
+                                                                     .map(|param| String::from(param["name"].as_str().unwrap()))
+                                                                     .collect();
+
+            assert_eq!(described, params, "path parameters of {} {}", method, path);
+        }
+    }
+
+    fn query_param(doc: &serde_json::Value, path: &str, name: &str) -> serde_json::Value {
+        doc["paths"][path]["get"]["parameters"].as_array()
+                                               .unwrap()
+                                               .iter()
+                                               .find(|param| param["name"] == name && param["in"] == "query")
+                                               .unwrap_or_else(|| panic!("{} has no {} parameter", path, name))
+                                               .clone()
+    }
+
+    #[test]
+    fn api_doc_describes_query_strings() {
+        let doc = serde_json::to_value(views::ApiDoc::openapi()).unwrap();
+
+        assert_eq!(query_param(&doc, "/v1/langs/{lang}/words", "like")["required"], true);
+        assert_eq!(query_param(&doc, "/v1/langs/{lang}/words", "limit")["required"], false);
+        assert_eq!(query_param(&doc, "/langs/{lang}/words/{word}", "fields")["required"], false);
+        assert_eq!(query_param(&doc, "/langs/{lang}/export", "generated")["required"], false);
+    }
+
+    #[test]
+    fn api_doc_describes_language_codes() {
+        let doc = serde_json::to_value(views::ApiDoc::openapi()).unwrap();
+
+        for schema in ["Language", "v1.Language"] {
+            let codes = &doc["components"]["schemas"][schema]["properties"]["codes"];
+            assert_eq!(codes["$ref"], "#/components/schemas/Codes", "{}", schema);
+        }
+    }
+
+    #[test]
+    fn legacy_catalogs_can_be_exported_and_packed() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        testing::legacy_catalog(&WordDb::new(&settings, "inflectived.db"));
+
+        let db = open_db(&settings);
+        let lang = db.get_installed_lang("pol").unwrap();
+
+        let mut exported = Vec::new();
+        WordDb::export_entries(&db.read_lang(&lang).ok().unwrap(), true, &mut exported).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), format!("{}\n", KOT));
+
+        let manifest = snapshot::pack(&db, &settings, &lang, Vec::new()).ok().unwrap();
+        assert_eq!(manifest.entries, 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! Checking the databases for corruption and compacting them
+
+use std::fmt;
+use std::fs;
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::database::WordDb;
+use crate::pool;
+
+/// Result of maintaining a single database
+pub struct DbReport {
+    /// `catalog`, or the code of the language
+    pub name: String,
+    /// Problems found by SQLite's integrity check
+    pub problems: Vec<String>,
+    /// Words whose type isn't in the types table, for language databases
+    pub dangling_words: Option<u64>,
+    pub size_before: u64,
+    pub size_after: u64,
+    /// Why maintenance stopped short, e.g. because the database was locked
+    pub error: Option<String>,
+}
+
+impl DbReport {
+    fn healthy(&self) -> bool {
+        self.problems.is_empty() && self.dangling_words.unwrap_or(0) == 0 && self.error.is_none()
+    }
+}
+
+pub struct Report {
+    pub databases: Vec<DbReport>,
+    /// Language databases with no installed language
+    pub orphaned_files: Vec<String>,
+    /// Tables of languages left in the catalog, from before languages had
+    /// files of their own
+    pub orphaned_tables: Vec<String>,
+    /// Installed languages whose database is gone
+    pub missing_files: Vec<String>,
+}
+
+impl Report {
+    pub fn healthy(&self) -> bool {
+        self.databases.iter().all(|db| db.healthy())
+            && self.orphaned_files.is_empty()
+            && self.orphaned_tables.is_empty()
+            && self.missing_files.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for db in &self.databases {
+            let state = if db.healthy() { "ok" } else { "PROBLEMS FOUND" };
+
+            writeln!(f, "{}: {}", db.name, state)?;
+
+            for problem in &db.problems {
+                writeln!(f, "    integrity: {}", problem)?;
+            }
+
+            if let Some(dangling_words) = db.dangling_words {
+                if dangling_words > 0 {
+                    writeln!(f, "    {} words have a type missing from the types table", dangling_words)?;
+                }
+            }
+
+            if let Some(error) = &db.error {
+                writeln!(f, "    error: {}", error)?;
+            }
+
+            writeln!(f, "    size: {} -> {} bytes", db.size_before, db.size_after)?;
+        }
+
+        for code in &self.orphaned_files {
+            writeln!(f, "Orphaned language database, not installed: {}", code)?;
+        }
+
+        for table in &self.orphaned_tables {
+            writeln!(f, "Orphaned table in the catalog: {}", table)?;
+        }
+
+        for code in &self.missing_files {
+            writeln!(f, "Missing language database, installed: {}", code)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Size of a database in bytes, including its write-ahead log
+fn size(path: &str) -> u64 {
+    [String::from(path), format!("{}-wal", path)].iter()
+                                                 .filter_map(|path| fs::metadata(path).ok())
+                                                 .map(|metadata| metadata.len())
+                                                 .sum()
+}
+
+/// Check, analyze and vacuum a database
+fn maintain_db(name: &str, path: &str, lang: bool) -> DbReport {
+    let mut report = DbReport {
+        name: String::from(name),
+        problems: Vec::new(),
+        dangling_words: None,
+        size_before: size(path),
+        size_after: 0,
+        error: None,
+    };
+
+    if let Err(e) = check_and_compact(path, lang, &mut report) {
+        report.error = Some(e.to_string());
+    }
+
+    report.size_after = size(path);
+
+    report
+}
+
+fn check_and_compact(path: &str, lang: bool, report: &mut DbReport) -> rusqlite::Result<()> {
+    // Opened without being created, should it have disappeared meanwhile
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    conn.busy_timeout(pool::BUSY_TIMEOUT)?;
+
+    let mut statement = conn.prepare("PRAGMA integrity_check")?;
+    report.problems = statement.query_map([], |row| row.get::<_, String>(0))?
+                               .collect::<rusqlite::Result<Vec<String>>>()?
+                               .into_iter()
+                               .filter(|problem| problem != "ok")
+                               .collect();
+    drop(statement);
+
+    if lang {
+        report.dangling_words = Some(conn.query_row(
+            "SELECT count(*)
+            FROM words
+            LEFT JOIN types
+            ON types.id = words.type_id
+            WHERE types.id IS NULL",
+            [],
+            |row| row.get(0)
+        )?);
+    }
+
+    // Rewriting a corrupted database could lose what's left of it
+    if !report.problems.is_empty() {
+        return Ok(());
+    }
+
+    conn.execute_batch("ANALYZE; VACUUM;")?;
+
+    // The vacuumed database goes through the write-ahead log, so it has to be
+    // checkpointed for the file to actually shrink
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+    Ok(())
+}
+
+/// Tables of languages left in the catalog
+fn orphaned_tables(path: &str) -> rusqlite::Result<Vec<String>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut statement = conn.prepare(
+        "SELECT name
+        FROM sqlite_master
+        WHERE type = 'table'
+        AND (name LIKE '%\\_words' ESCAPE '\\' OR name LIKE '%\\_types' ESCAPE '\\')
+        ORDER BY name"
+    )?;
+
+    let tables = statement.query_map([], |row| row.get(0))?
+                          .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(tables)
+}
+
+/// Check every database, look for languages missing a database or the other
+/// way around, and compact the databases that are sound
+pub fn maintain(db: &WordDb) -> Report {
+    let installed_langs = db.installed_langs();
+    let lang_files = db.lang_files();
+
+    let mut databases = vec![maintain_db("catalog", db.catalog_path(), false)];
+
+    for code in &lang_files {
+        databases.push(maintain_db(code, &db.lang_path(code), true));
+    }
+
+    let orphaned_tables = match orphaned_tables(db.catalog_path()) {
+        Ok(tables) => tables,
+        Err(e) => {
+            databases[0].error.get_or_insert(e.to_string());
+            Vec::new()
+        }
+    };
+
+    Report {
+        databases,
+        orphaned_files: lang_files.iter()
+                                  .filter(|code| !installed_langs.iter().any(|lang| &lang.code == *code))
+                                  .cloned()
+                                  .collect(),
+        orphaned_tables,
+        missing_files: installed_langs.iter()
+                                      .filter(|lang| !lang_files.contains(&lang.code))
+                                      .map(|lang| lang.code.clone())
+                                      .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[tokio::test]
+    async fn installed_languages_are_healthy() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        let report = maintain(&db);
+
+        assert!(report.healthy(), "{}", report);
+        assert_eq!(report.databases.iter().map(|db| db.name.as_str()).collect::<Vec<_>>(), ["catalog", "pol"]);
+        assert_eq!(report.databases[0].dangling_words, None);
+        assert_eq!(report.databases[1].dangling_words, Some(0));
+        assert!(report.databases[1].size_after > 0);
+        // The write-ahead log was checkpointed into the database
+        assert_eq!(fs::metadata(format!("{}-wal", db.lang_path("pol"))).map_or(0, |metadata| metadata.len()), 0);
+    }
+
+    #[tokio::test]
+    async fn words_without_a_type_are_found() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        db.connect_lang("pol").unwrap().execute("DELETE FROM types", []).unwrap();
+
+        let report = maintain(&db);
+
+        assert!(!report.healthy());
+        assert!(report.databases[1].dangling_words.unwrap() > 0);
+        assert!(report.to_string().contains("have a type missing from the types table"), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn files_and_languages_are_matched() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        // Left by an interrupted removal
+        fs::copy(db.lang_path("pol"), db.lang_path("deu")).unwrap();
+        fs::remove_file(db.lang_path("pol")).unwrap();
+
+        let report = maintain(&db);
+
+        assert!(!report.healthy());
+        assert_eq!(report.orphaned_files, ["deu"]);
+        assert_eq!(report.missing_files, ["pol"]);
+        assert!(report.to_string().contains("Orphaned language database, not installed: deu"), "{}", report);
+        assert!(report.to_string().contains("Missing language database, installed: pol"), "{}", report);
+    }
+
+    #[test]
+    fn tables_left_in_the_catalog_are_found() {
+        let dir = TempDir::new();
+        let db = WordDb::new(&testing::settings(&dir), "inflectived.db");
+
+        db.connect().unwrap().execute_batch("
+        CREATE TABLE pol_types (id INTEGER PRIMARY KEY, name TINYTEXT);
+        CREATE TABLE pol_words (id INTEGER PRIMARY KEY, word TINYTEXT);
+        ").unwrap();
+
+        let report = maintain(&db);
+
+        assert!(!report.healthy());
+        assert_eq!(report.orphaned_tables, ["pol_types", "pol_words"]);
+    }
+}
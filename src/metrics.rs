@@ -0,0 +1,379 @@
+//! Prometheus metrics, in the text exposition format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::{get, Request, Response, Data, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::response::content;
+
+use crate::database::WordDb;
+use crate::jobs::{Jobs, JobState, JobStatus};
+
+// Upper bounds of the buckets of request latency histograms, in seconds
+const REQUEST_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Upgrades take minutes rather than milliseconds
+const UPGRADE_BUCKETS: &[f64] = &[10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0];
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// Observations in each bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.buckets.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Write the histogram's series, with `labels` already formatted
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative).unwrap();
+        }
+
+        writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count).unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+/// Durations of finished upgrades, recorded as they finish since finished
+/// jobs are only kept for a while
+///
+/// Cancelled ones say nothing about how long upgrades take, so they're left
+/// out.
+pub struct UpgradeDurations {
+    succeeded: Mutex<Histogram>,
+    failed: Mutex<Histogram>,
+}
+
+impl Default for UpgradeDurations {
+    fn default() -> Self {
+        Self {
+            succeeded: Mutex::new(Histogram::new(UPGRADE_BUCKETS)),
+            failed: Mutex::new(Histogram::new(UPGRADE_BUCKETS)),
+        }
+    }
+}
+
+impl UpgradeDurations {
+    pub fn observe(&self, status: &JobStatus) {
+        let histogram = match status.state {
+            JobState::Succeeded => &self.succeeded,
+            JobState::Failed => &self.failed,
+            _ => return
+        };
+
+        if let Some(finished) = status.finished {
+            histogram.lock().unwrap().observe((finished - status.started) as f64);
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        self.succeeded.lock().unwrap().write(out, "inflectived_upgrade_duration_seconds", "outcome=\"succeeded\"");
+        self.failed.lock().unwrap().write(out, "inflectived_upgrade_duration_seconds", "outcome=\"failed\"");
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Keyed by method, route and status
+    requests: BTreeMap<(String, String, u16), u64>,
+    /// Keyed by method and route
+    request_durations: BTreeMap<(String, String), Histogram>,
+    /// Keyed by language and whether the word was found
+    lookups: BTreeMap<(String, bool), u64>,
+    /// Keyed by language
+    autocomplete_durations: BTreeMap<String, Histogram>,
+    /// Keyed by language
+    skipped_entries: BTreeMap<String, u64>,
+}
+
+/// Metrics collected since the daemon started
+///
+/// Everything else, like the database size, is read when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+impl Metrics {
+    /// Count a word lookup
+    pub fn lookup(&self, lang: &str, found: bool) {
+        let mut registry = self.registry.lock().unwrap();
+
+        *registry.lookups.entry((String::from(lang), found)).or_insert(0) += 1;
+    }
+
+    /// Record how long searching for words containing a string took
+    pub fn autocomplete(&self, lang: &str, duration: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+
+        registry.autocomplete_durations.entry(String::from(lang))
+                                       .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+                                       .observe(duration.as_secs_f64());
+    }
+
+    /// Count an entry left out of a `/v1` lookup because it doesn't fit the
+    /// schema
+    pub fn skipped_entry(&self, lang: &str) {
+        let mut registry = self.registry.lock().unwrap();
+
+        *registry.skipped_entries.entry(String::from(lang)).or_insert(0) += 1;
+    }
+
+    fn request(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+
+        *registry.requests.entry((String::from(method), String::from(route), status))
+                          .or_insert(0) += 1;
+
+        registry.request_durations.entry((String::from(method), String::from(route)))
+                                  .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+                                  .observe(duration.as_secs_f64());
+    }
+
+    fn render(&self, db: &WordDb, jobs: &Jobs) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        writeln!(out, "# HELP inflectived_http_requests_total HTTP requests handled, by route pattern.").unwrap();
+        writeln!(out, "# TYPE inflectived_http_requests_total counter").unwrap();
+        for ((method, route, status), count) in &registry.requests {
+            writeln!(out, "inflectived_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                     method, escape(route), status, count).unwrap();
+        }
+
+        writeln!(out, "# HELP inflectived_http_request_duration_seconds Time taken to handle HTTP requests.").unwrap();
+        writeln!(out, "# TYPE inflectived_http_request_duration_seconds histogram").unwrap();
+        for ((method, route), histogram) in &registry.request_durations {
+            histogram.write(&mut out, "inflectived_http_request_duration_seconds",
+                            &format!("method=\"{}\",route=\"{}\"", method, escape(route)));
+        }
+
+        writeln!(out, "# HELP inflectived_lookups_total Word lookups, by whether the word was found.").unwrap();
+        writeln!(out, "# TYPE inflectived_lookups_total counter").unwrap();
+        for ((lang, found), count) in &registry.lookups {
+            let result = if *found { "hit" } else { "miss" };
+
+            writeln!(out, "inflectived_lookups_total{{lang=\"{}\",result=\"{}\"}} {}",
+                     escape(lang), result, count).unwrap();
+        }
+
+        writeln!(out, "# HELP inflectived_autocomplete_duration_seconds Time taken to search for words containing a string.").unwrap();
+        writeln!(out, "# TYPE inflectived_autocomplete_duration_seconds histogram").unwrap();
+        for (lang, histogram) in &registry.autocomplete_durations {
+            histogram.write(&mut out, "inflectived_autocomplete_duration_seconds",
+                            &format!("lang=\"{}\"", escape(lang)));
+        }
+
+        writeln!(out, "# HELP inflectived_v1_skipped_entries_total Entries left out of /v1 lookups because they don't fit the schema.").unwrap();
+        writeln!(out, "# TYPE inflectived_v1_skipped_entries_total counter").unwrap();
+        for (lang, count) in &registry.skipped_entries {
+            writeln!(out, "inflectived_v1_skipped_entries_total{{lang=\"{}\"}} {}", escape(lang), count).unwrap();
+        }
+
+        drop(registry);
+
+        writeln!(out, "# HELP inflectived_database_size_bytes Size of the database, its write-ahead log included.").unwrap();
+        writeln!(out, "# TYPE inflectived_database_size_bytes gauge").unwrap();
+        writeln!(out, "inflectived_database_size_bytes {}", db.size()).unwrap();
+
+        writeln!(out, "# HELP inflectived_lang_info Installed languages and the version they were upgraded with.").unwrap();
+        writeln!(out, "# TYPE inflectived_lang_info gauge").unwrap();
+        for lang in db.installed_langs().iter() {
+            let version = match &lang.version {
+                Some(version) => format!("{}.{}.{}", version.0, version.1, version.2),
+                None => String::new()
+            };
+
+            writeln!(out, "inflectived_lang_info{{lang=\"{}\",name=\"{}\",version=\"{}\"}} 1",
+                     escape(&lang.code), escape(&lang.name), version).unwrap();
+        }
+
+        writeln!(out, "# HELP inflectived_lang_upgraded_timestamp_seconds Unix time installed languages were last upgraded at.").unwrap();
+        writeln!(out, "# TYPE inflectived_lang_upgraded_timestamp_seconds gauge").unwrap();
+        for lang in db.installed_langs().iter() {
+            if let Some(upgraded) = lang.upgraded {
+                writeln!(out, "inflectived_lang_upgraded_timestamp_seconds{{lang=\"{}\"}} {}",
+                         escape(&lang.code), upgraded).unwrap();
+            }
+        }
+
+        let statuses = jobs.list();
+
+        writeln!(out, "# HELP inflectived_upgrade_jobs Running upgrade jobs and the last finished ones, by state.").unwrap();
+        writeln!(out, "# TYPE inflectived_upgrade_jobs gauge").unwrap();
+        for (state, name) in [(JobState::Running, "running"),
+                              (JobState::Succeeded, "succeeded"),
+                              (JobState::Failed, "failed"),
+                              (JobState::Cancelled, "cancelled")] {
+            let count = statuses.iter().filter(|status| status.state == state).count();
+
+            writeln!(out, "inflectived_upgrade_jobs{{state=\"{}\"}} {}", name, count).unwrap();
+        }
+
+        writeln!(out, "# HELP inflectived_upgrade_duration_seconds Time taken by finished upgrade jobs.").unwrap();
+        writeln!(out, "# TYPE inflectived_upgrade_duration_seconds histogram").unwrap();
+        jobs.durations.write(&mut out);
+
+        out
+    }
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Times every request, for the request metrics
+pub struct RequestTimer;
+
+/// When the request came in, kept in its local cache
+struct Started(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| Started(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Started(started) = req.local_cache(|| Started(None));
+
+        if let (Some(started), Some(metrics)) = (started, req.rocket().state::<Metrics>()) {
+            // The route's pattern rather than the path, so that words don't
+            // each get their own series
+            let route = match req.route() {
+                Some(route) => route.uri.to_string(),
+                None => String::from("unmatched")
+            };
+
+            metrics.request(req.method().as_str(), &route, res.status().code, started.elapsed());
+        }
+    }
+}
+
+/// Get metrics in the Prometheus text format
+#[utoipa::path(
+    tag = "metrics",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text exposition format", body = String, content_type = "text/plain")
+    )
+)]
+#[get("/metrics")]
+pub fn get_metrics(metrics: &State<Metrics>, db: &State<WordDb>, jobs: &State<Jobs>) -> content::RawText<String> {
+    content::RawText(metrics.render(db, jobs))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+    use crate::language::Language;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn histograms_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 2.0]);
+
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(3.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test", "lang=\"pol\"");
+
+        assert_eq!(out, "\
+test_bucket{lang=\"pol\",le=\"1\"} 1
+test_bucket{lang=\"pol\",le=\"2\"} 2
+test_bucket{lang=\"pol\",le=\"+Inf\"} 3
+test_sum{lang=\"pol\"} 5.5
+test_count{lang=\"pol\"} 3
+");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn upgrade_durations_leave_cancelled_jobs_out() {
+        let jobs = Jobs::default();
+
+        for state in [JobState::Succeeded, JobState::Failed, JobState::Cancelled] {
+            let job = jobs.add_running(&Language::new("pol", "Polish"));
+            job.finish(state, None);
+            jobs.durations.observe(&job.status());
+        }
+
+        let mut out = String::new();
+        jobs.durations.write(&mut out);
+
+        assert!(out.contains("inflectived_upgrade_duration_seconds_count{outcome=\"succeeded\"} 1"));
+        assert!(out.contains("inflectived_upgrade_duration_seconds_count{outcome=\"failed\"} 1"));
+        assert!(!out.contains("cancelled"));
+    }
+
+    #[rocket::async_test]
+    async fn scrapes() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        let client = Client::tracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        client.get("/langs/pol/words/kot").dispatch().await;
+        client.get("/langs/pol/words/kota").dispatch().await;
+        client.get("/langs/pol/words/missing").dispatch().await;
+        client.get("/nowhere").dispatch().await;
+
+        client.rocket().state::<Jobs>().unwrap().add_running(&Language::new("ger", "German"));
+
+        let out = client.get("/metrics").dispatch().await.into_string().await.unwrap();
+
+        assert!(out.contains("inflectived_lookups_total{lang=\"pol\",result=\"hit\"} 2"));
+        assert!(out.contains("inflectived_lookups_total{lang=\"pol\",result=\"miss\"} 1"));
+        // Words don't get series of their own
+        assert!(out.contains(
+            "inflectived_http_requests_total{method=\"GET\",route=\"/langs/<lang>/words/<word>?<params..>\",status=\"200\"} 3"
+        ));
+        assert!(out.contains("inflectived_http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1"));
+        assert!(out.contains("inflectived_lang_info{lang=\"pol\",name=\"Polish\",version=\""));
+        assert!(out.contains("inflectived_upgrade_jobs{state=\"running\"} 1"));
+    }
+}
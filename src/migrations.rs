@@ -0,0 +1,216 @@
+//! Schema migrations of language databases built by older daemons
+//!
+//! A language database records how many migrations were applied to it as
+//! its `user_version`. Those built before it did have every migration up to
+//! the daemon that built them.
+
+use rusqlite::Connection;
+
+use crate::database::{WordDb, DbError};
+use crate::language::{Language, Status};
+use crate::version::Version;
+
+pub struct Migration {
+    /// Version of the daemon that introduced the change
+    pub version: Version,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Applied in this order, so keep it sorted by version. The tables created
+/// by `WordDb::create_lang_tables` already include all of them
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// Languages built by daemons older than this were parsed differently, so
+/// they have to be upgraded again rather than migrated
+pub const REBUILD_BEFORE: Version = Version(0, 1, 0);
+
+pub enum Outcome {
+    UpToDate,
+    /// Descriptions of the migrations applied
+    Migrated(Vec<&'static str>),
+    NeedsRebuild,
+}
+
+pub fn needs_rebuild(version: &Version) -> bool {
+    *version < REBUILD_BEFORE
+}
+
+/// How many of `migrations` were applied to a language database built by
+/// `built`
+pub fn schema_version(conn: &Connection, built: &Version, migrations: &[Migration]) -> rusqlite::Result<usize> {
+    let user_version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if user_version > 0 {
+        return Ok(user_version);
+    }
+
+    Ok(migrations.iter().take_while(|migration| migration.version <= *built).count())
+}
+
+/// Apply the `migrations` a language database is missing, in a single
+/// transaction, returning their descriptions
+///
+/// Takes the migrations rather than using `MIGRATIONS` so they can be
+/// tested.
+pub fn apply(conn: &mut Connection,
+             built: &Version,
+             migrations: &[Migration]) -> rusqlite::Result<Vec<&'static str>> {
+    let transaction = conn.transaction()?;
+
+    let applied = schema_version(&transaction, built, migrations)?;
+    let pending = &migrations[applied.min(migrations.len())..];
+
+    if pending.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    for migration in pending {
+        transaction.execute_batch(migration.sql)?;
+    }
+
+    transaction.execute_batch(&format!("PRAGMA user_version = {}", migrations.len()))?;
+    transaction.commit()?;
+
+    Ok(pending.iter().map(|migration| migration.description).collect())
+}
+
+/// Migrate every installed language that can be
+pub fn migrate_all(db: &WordDb) -> Vec<(Language, Result<Outcome, DbError>)> {
+    let lang_files = db.lang_files();
+    let mut outcomes = Vec::new();
+
+    for lang in db.installed_langs().iter() {
+        // Left for `maintain` to report
+        if !lang_files.contains(&lang.code) {
+            continue;
+        }
+
+        let outcome = if lang.status == Status::NeedsRebuild {
+            Ok(Outcome::NeedsRebuild)
+        } else {
+            match db.migrate_lang(lang) {
+                Ok(applied) if applied.is_empty() => Ok(Outcome::UpToDate),
+                Ok(applied) => Ok(Outcome::Migrated(applied)),
+                Err(e) => Err(e)
+            }
+        };
+
+        outcomes.push((lang.clone(), outcome));
+    }
+
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: Version(0, 2, 0),
+            description: "Index words by type",
+            sql: "CREATE INDEX type_index ON words (type_id);",
+        },
+        Migration {
+            version: Version(0, 3, 0),
+            description: "Add a frequency to words",
+            sql: "ALTER TABLE words ADD COLUMN frequency INTEGER;",
+        },
+    ];
+
+    fn lang_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("
+        CREATE TABLE types (id INTEGER PRIMARY KEY, name TINYTEXT UNIQUE NOT NULL);
+        CREATE TABLE words (id INTEGER PRIMARY KEY, word TINYTEXT NOT NULL, type_id INTEGER NOT NULL, content MEDIUMTEXT NOT NULL);
+        ").unwrap();
+
+        conn
+    }
+
+    fn has_column(conn: &Connection, column: &str) -> bool {
+        conn.prepare(&format!("SELECT {} FROM words", column)).is_ok()
+    }
+
+    #[test]
+    fn versions_fall_back_to_the_daemon_that_built_the_database() {
+        let conn = lang_db();
+
+        assert_eq!(schema_version(&conn, &Version(0, 1, 0), TEST_MIGRATIONS).unwrap(), 0);
+        assert_eq!(schema_version(&conn, &Version(0, 2, 0), TEST_MIGRATIONS).unwrap(), 1);
+        assert_eq!(schema_version(&conn, &Version(0, 2, 5), TEST_MIGRATIONS).unwrap(), 1);
+        assert_eq!(schema_version(&conn, &Version(1, 0, 0), TEST_MIGRATIONS).unwrap(), 2);
+    }
+
+    #[test]
+    fn recorded_versions_come_first() {
+        let conn = lang_db();
+        conn.execute_batch("PRAGMA user_version = 1").unwrap();
+
+        assert_eq!(schema_version(&conn, &Version(1, 0, 0), TEST_MIGRATIONS).unwrap(), 1);
+    }
+
+    #[test]
+    fn missing_migrations_are_applied_and_recorded() {
+        let mut conn = lang_db();
+
+        let applied = apply(&mut conn, &Version(0, 1, 0), TEST_MIGRATIONS).unwrap();
+
+        assert_eq!(applied, ["Index words by type", "Add a frequency to words"]);
+        assert!(has_column(&conn, "frequency"));
+        assert_eq!(schema_version(&conn, &Version(0, 1, 0), TEST_MIGRATIONS).unwrap(), 2);
+
+        assert!(apply(&mut conn, &Version(0, 1, 0), TEST_MIGRATIONS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_newer_migrations_are_applied() {
+        let mut conn = lang_db();
+        conn.execute_batch("CREATE INDEX type_index ON words (type_id)").unwrap();
+
+        let applied = apply(&mut conn, &Version(0, 2, 0), TEST_MIGRATIONS).unwrap();
+
+        assert_eq!(applied, ["Add a frequency to words"]);
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back() {
+        let mut conn = lang_db();
+        // Makes the second migration fail
+        conn.execute_batch("ALTER TABLE words ADD COLUMN frequency INTEGER").unwrap();
+
+        assert!(apply(&mut conn, &Version(0, 1, 0), TEST_MIGRATIONS).is_err());
+
+        assert_eq!(schema_version(&conn, &Version(0, 1, 0), TEST_MIGRATIONS).unwrap(), 0);
+        assert!(conn.query_row("SELECT 1 FROM sqlite_master WHERE name = 'type_index'", [], |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn read_only_databases_are_access_denied() {
+        let dir = TempDir::new();
+        let path = format!("{}/pol.db", dir.0);
+        Connection::open(&path).unwrap()
+                               .execute("CREATE TABLE words (id INTEGER PRIMARY KEY, type_id INTEGER)", [])
+                               .unwrap();
+
+        let mut conn = Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        let result = apply(&mut conn, &Version(0, 1, 0), TEST_MIGRATIONS).map_err(DbError::from);
+
+        assert!(matches!(result, Err(DbError::AccessDenied)));
+    }
+
+    #[tokio::test]
+    async fn new_databases_have_every_migration() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let lang = testing::install(&settings, &db, "pol").await;
+
+        let conn = db.connect_lang("pol").unwrap();
+
+        assert_eq!(schema_version(&conn, &Version(0, 0, 0), MIGRATIONS).unwrap(), MIGRATIONS.len());
+        assert!(db.migrate_lang(&lang).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+
+/// How long a connection waits for a lock held by another one before failing
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Connections kept open when they're given back, beyond which they're closed
+const MAX_IDLE: usize = 16;
+
+// Prepared statements cached per connection. Queries are per language, so
+// this covers a few statements for every language
+const STATEMENT_CACHE: usize = 128;
+
+/// A pool of read-only connections to a database
+///
+/// Connections are reused along with their cached prepared statements. The
+/// database is expected to be in WAL mode, so they can read while another
+/// connection is writing.
+///
+/// Reading a WAL database still needs its `-shm` file, which SQLite creates
+/// when missing, even through a read-only connection. The data directory
+/// must therefore be writable by the user the daemon runs as, which upgrades
+/// need anyway; otherwise connections fail to open.
+pub struct Pool {
+    db_path: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+    pub fn new(db_path: &str) -> Arc<Self> {
+        Arc::new(Self {
+            db_path: String::from(db_path),
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Take an idle connection, or open a new one if there's none
+    pub fn get(self: &Arc<Self>) -> PooledConnection {
+        self.try_get().unwrap()
+    }
+
+    pub fn try_get(self: &Arc<Self>) -> rusqlite::Result<PooledConnection> {
+        let idle = self.idle.lock().unwrap().pop();
+
+        let conn = match idle {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open_with_flags(
+                    &self.db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                )?;
+
+                conn.busy_timeout(BUSY_TIMEOUT)?;
+                conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE);
+
+                conn
+            }
+        };
+
+        Ok(PooledConnection {
+            pool: self.clone(),
+            conn: Some(conn),
+        })
+    }
+}
+
+/// A connection that goes back to its pool when dropped
+///
+/// It owns a handle on the pool, so it can be moved into blocking tasks.
+pub struct PooledConnection {
+    pool: Arc<Pool>,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let mut idle = self.pool.idle.lock().unwrap();
+
+        if idle.len() < MAX_IDLE {
+            idle.push(self.conn.take().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn database(dir: &TempDir) -> String {
+        let path = format!("{}/test.db", dir.0);
+
+        let conn = Connection::open(&path).unwrap();
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).unwrap();
+        conn.execute_batch("CREATE TABLE words (word TEXT); INSERT INTO words VALUES ('kot');").unwrap();
+
+        path
+    }
+
+    #[test]
+    fn connections_are_reused() {
+        let dir = TempDir::new();
+        let pool = Pool::new(&database(&dir));
+
+        // Temporary tables only live as long as their connection
+        pool.get().execute("CREATE TEMP TABLE marker (id INTEGER)", []).unwrap();
+
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+        assert!(pool.get().execute("SELECT * FROM temp.marker", []).is_ok());
+    }
+
+    #[test]
+    fn only_some_idle_connections_are_kept() {
+        let dir = TempDir::new();
+        let pool = Pool::new(&database(&dir));
+
+        let conns: Vec<PooledConnection> = (0..MAX_IDLE + 4).map(|_| pool.get()).collect();
+        drop(conns);
+
+        assert_eq!(pool.idle.lock().unwrap().len(), MAX_IDLE);
+    }
+
+    #[test]
+    fn connections_are_read_only() {
+        let dir = TempDir::new();
+        let pool = Pool::new(&database(&dir));
+        let conn = pool.get();
+
+        let word: String = conn.query_row("SELECT word FROM words", [], |row| row.get(0)).unwrap();
+
+        assert_eq!(word, "kot");
+        assert!(conn.execute("DELETE FROM words", []).is_err());
+    }
+
+    #[test]
+    fn reads_go_on_while_writing() {
+        let dir = TempDir::new();
+        let path = database(&dir);
+        let pool = Pool::new(&path);
+
+        let mut writer = Connection::open(&path).unwrap();
+        let transaction = writer.transaction().unwrap();
+        transaction.execute("DELETE FROM words", []).unwrap();
+
+        let count: i64 = pool.get().query_row("SELECT count(*) FROM words", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        transaction.commit().unwrap();
+
+        let count: i64 = pool.get().query_row("SELECT count(*) FROM words", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn missing_databases_fail_to_open() {
+        let dir = TempDir::new();
+        let pool = Pool::new(&format!("{}/missing.db", dir.0));
+
+        assert!(pool.try_get().is_err());
+    }
+}
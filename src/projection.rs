@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+/// A set of dotted field paths to keep in entries, e.g. `word,senses.glosses`
+///
+/// Paths go through arrays, so `senses.glosses` keeps the glosses of every
+/// sense.
+#[derive(Debug)]
+pub struct Projection(Option<BTreeMap<String, Projection>>);
+
+impl Projection {
+    pub fn parse(fields: &str) -> Self {
+        let mut projection = Self(Some(BTreeMap::new()));
+
+        for path in fields.split(',').map(|path| path.trim()).filter(|path| !path.is_empty()) {
+            projection.insert(&path.split('.').collect::<Vec<&str>>());
+        }
+
+        projection
+    }
+
+    fn insert(&mut self, path: &[&str]) {
+        let children = match &mut self.0 {
+            Some(children) => children,
+            // The whole field is already kept
+            None => return
+        };
+
+        if let Some((field, rest)) = path.split_first() {
+            let child = children.entry(String::from(*field))
+                                .or_insert_with(|| Self(Some(BTreeMap::new())));
+
+            if rest.is_empty() {
+                child.0 = None;
+            } else {
+                child.insert(rest);
+            }
+        }
+    }
+
+    /// Trim `value` down to the projected fields
+    pub fn apply(&self, value: Value) -> Value {
+        let children = match &self.0 {
+            Some(children) => children,
+            None => return value
+        };
+
+        match value {
+            Value::Object(mut object) => {
+                let mut projected = Map::new();
+
+                for (field, child) in children {
+                    if let Some(value) = object.remove(field) {
+                        projected.insert(field.clone(), child.apply(value));
+                    }
+                }
+
+                Value::Object(projected)
+            },
+            Value::Array(array) => {
+                Value::Array(array.into_iter().map(|value| self.apply(value)).collect())
+            },
+            value => value
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn entry() -> Value {
+        json!({
+            "word": "kot",
+            "pos": "noun",
+            "senses": [
+                {"glosses": ["cat"], "tags": ["masculine"]},
+                {"glosses": ["tomcat"], "tags": ["animal"]}
+            ],
+            "forms": [{"form": "koty", "tags": ["plural"]}]
+        })
+    }
+
+    #[test]
+    fn top_level_fields() {
+        assert_eq!(Projection::parse("word,pos").apply(entry()),
+                   json!({"word": "kot", "pos": "noun"}));
+    }
+
+    #[test]
+    fn paths_go_through_arrays() {
+        assert_eq!(Projection::parse("senses.glosses").apply(entry()),
+                   json!({"senses": [{"glosses": ["cat"]}, {"glosses": ["tomcat"]}]}));
+    }
+
+    #[test]
+    fn whole_fields_win_over_their_paths() {
+        let expected = json!({"forms": [{"form": "koty", "tags": ["plural"]}]});
+
+        assert_eq!(Projection::parse("forms,forms.form").apply(entry()), expected);
+        assert_eq!(Projection::parse("forms.form,forms").apply(entry()), expected);
+    }
+
+    #[test]
+    fn missing_fields_are_left_out() {
+        assert_eq!(Projection::parse("word,etymology_text,senses.examples").apply(entry()),
+                   json!({"word": "kot", "senses": [{}, {}]}));
+    }
+
+    #[test]
+    fn paths_into_scalars_keep_them() {
+        assert_eq!(Projection::parse("word.length").apply(entry()), json!({"word": "kot"}));
+    }
+
+    #[test]
+    fn blank_paths_are_ignored() {
+        assert_eq!(Projection::parse(" word , ,").apply(entry()), json!({"word": "kot"}));
+        assert_eq!(Projection::parse("").apply(entry()), json!({}));
+    }
+}
@@ -0,0 +1,249 @@
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+use config::{Config, ConfigError, Environment, File, FileFormat};
+use nix::unistd::geteuid;
+use serde::Deserialize;
+
+const SYSTEM_CONFIG: &str = "/etc/inflectived/config.toml";
+
+/// Settings of the daemon, layered from lowest to highest priority:
+///
+/// 1. Defaults, which depend on whether running as root: system-wide
+///    directories if so, otherwise the user's XDG directories
+/// 2. The system config file, `/etc/inflectived/config.toml`
+/// 3. The user config file, `$XDG_CONFIG_HOME/inflectived/config.toml`
+/// 4. `INFLECTIVED_*` environment variables, e.g. `INFLECTIVED_PORT`
+/// 5. Command line flags
+#[derive(Debug, Clone, Deserialize)]
+pub struct Settings {
+    pub data_dir: String,
+    pub cache_dir: String,
+    pub frontend_dir: String,
+    pub address: String,
+    pub port: u16,
+    /// URL of a language's dump, with `{lang}` standing for its name
+    pub source_url: String,
+    /// Index of the dumps that the catalog of languages is refreshed from,
+    /// as a URL or a local file
+    pub index_url: String,
+    /// User to switch to once the server is listening, when started as root
+    pub user: Option<String>,
+    /// One of "off", "error", "warn", "info", "debug" or "trace"
+    pub log_level: String,
+    /// Either "human" or "json"
+    pub log_format: String,
+    /// Leave looked up words out of request logs
+    pub redact_words: bool,
+    /// Seconds in-flight requests get to finish on shutdown
+    pub shutdown_grace: u32,
+    /// Upgrade languages built by older versions in the background on
+    /// startup
+    pub rebuild_outdated: bool,
+}
+
+impl Settings {
+    /// Config files in the order they're read, whether they exist or not
+    pub fn files() -> Vec<String> {
+        let mut files = vec![String::from(SYSTEM_CONFIG)];
+
+        if let Some(config_home) = xdg_dir("XDG_CONFIG_HOME", ".config") {
+            files.push(format!("{}/inflectived/config.toml", config_home));
+        }
+
+        files
+    }
+
+    /// Load the settings, with `overrides` being the command line flags that
+    /// were given
+    pub fn load(overrides: &[(&str, &str)]) -> Result<Self, ConfigError> {
+        Self::layer(&Self::files(), overrides)
+    }
+
+    fn layer(files: &[String], overrides: &[(&str, &str)]) -> Result<Self, ConfigError> {
+        let mut config = Config::new();
+
+        let data_home = xdg_dir("XDG_DATA_HOME", ".local/share");
+        let cache_home = xdg_dir("XDG_CACHE_HOME", ".cache");
+
+        match (geteuid().is_root(), data_home, cache_home) {
+            (false, Some(data_home), Some(cache_home)) => {
+                config.set_default("data_dir", format!("{}/inflectived", data_home))?;
+                config.set_default("cache_dir", format!("{}/inflectived", cache_home))?;
+            },
+            _ => {
+                config.set_default("data_dir", "/usr/share/inflectived")?;
+                config.set_default("cache_dir", "/var/cache/inflectived")?;
+            }
+        }
+        config.set_default("frontend_dir", "/opt/inflectived")?;
+        config.set_default("address", "0.0.0.0")?;
+        config.set_default("port", 8000)?;
+        config.set_default("source_url",
+                           "https://kaikki.org/dictionary/{lang}/kaikki.org-dictionary-{lang}.json")?;
+        config.set_default("index_url", "https://kaikki.org/dictionary/")?;
+        config.set_default("log_level", "info")?;
+        config.set_default("log_format", "human")?;
+        config.set_default("redact_words", false)?;
+        config.set_default("shutdown_grace", 5)?;
+        config.set_default("rebuild_outdated", false)?;
+
+        for file in files {
+            config.merge(File::new(file, FileFormat::Toml).required(false))?;
+        }
+
+        config.merge(Environment::with_prefix("INFLECTIVED"))?;
+
+        for (key, value) in overrides {
+            config.set(key, *value)?;
+        }
+
+        config.try_into()
+    }
+
+    pub fn source_url(&self, lang_name: &str) -> String {
+        self.source_url.replace("{lang}", lang_name)
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in Self::files() {
+            let state = if Path::new(&file).exists() { "read" } else { "not found" };
+            writeln!(f, "# {} ({})", file, state)?;
+        }
+
+        writeln!(f, "data_dir = {:?}", self.data_dir)?;
+        writeln!(f, "cache_dir = {:?}", self.cache_dir)?;
+        writeln!(f, "frontend_dir = {:?}", self.frontend_dir)?;
+        writeln!(f, "address = {:?}", self.address)?;
+        writeln!(f, "port = {}", self.port)?;
+        writeln!(f, "source_url = {:?}", self.source_url)?;
+        writeln!(f, "index_url = {:?}", self.index_url)?;
+        writeln!(f, "log_level = {:?}", self.log_level)?;
+        writeln!(f, "log_format = {:?}", self.log_format)?;
+        writeln!(f, "redact_words = {}", self.redact_words)?;
+        writeln!(f, "shutdown_grace = {}", self.shutdown_grace)?;
+        write!(f, "rebuild_outdated = {}", self.rebuild_outdated)?;
+
+        if let Some(user) = &self.user {
+            write!(f, "\nuser = {:?}", user)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An XDG base directory, falling back to its default under the home
+/// directory
+fn xdg_dir(var: &str, default: &str) -> Option<String> {
+    resolve_xdg_dir(env::var(var).ok(), env::var("HOME").ok(), default)
+}
+
+fn resolve_xdg_dir(dir: Option<String>, home: Option<String>, default: &str) -> Option<String> {
+    match dir {
+        Some(dir) if !dir.is_empty() => Some(dir),
+        _ => home.map(|home| format!("{}/{}", home, default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn config_file(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = format!("{}/{}", dir.0, name);
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = Settings::layer(&[], &[]).unwrap();
+
+        assert_eq!(settings.frontend_dir, "/opt/inflectived");
+        assert_eq!(settings.log_format, "human");
+        assert_eq!(settings.shutdown_grace, 5);
+        assert!(!settings.redact_words);
+        assert_eq!(settings.user, None);
+    }
+
+    #[test]
+    fn later_files_win() {
+        let dir = TempDir::new();
+        let system = config_file(&dir, "system.toml", "port = 8001\naddress = \"127.0.0.1\"");
+        let user = config_file(&dir, "user.toml", "port = 8002\nuser = \"inflectived\"");
+        let missing = format!("{}/missing.toml", dir.0);
+
+        let settings = Settings::layer(&[system, user, missing], &[]).unwrap();
+
+        assert_eq!(settings.port, 8002);
+        assert_eq!(settings.address, "127.0.0.1");
+        assert_eq!(settings.user.as_deref(), Some("inflectived"));
+    }
+
+    #[test]
+    fn flags_win_over_files() {
+        let dir = TempDir::new();
+        let file = config_file(&dir, "config.toml", "port = 8001\nredact_words = false");
+
+        let settings = Settings::layer(&[file], &[("port", "8003"), ("redact_words", "true")]).unwrap();
+
+        assert_eq!(settings.port, 8003);
+        assert!(settings.redact_words);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let dir = TempDir::new();
+        let file = config_file(&dir, "config.toml", "port = \"eighty\"");
+
+        assert!(Settings::layer(std::slice::from_ref(&file), &[]).is_err());
+        assert!(Settings::layer(&[], &[("shutdown_grace", "soon")]).is_err());
+
+        fs::write(&file, "port = [").unwrap();
+        assert!(Settings::layer(&[file], &[]).is_err());
+    }
+
+    #[test]
+    fn xdg_dirs() {
+        let home = || Some(String::from("/home/user"));
+
+        assert_eq!(resolve_xdg_dir(Some(String::from("/data")), home(), ".local/share").as_deref(),
+                   Some("/data"));
+        assert_eq!(resolve_xdg_dir(None, home(), ".local/share").as_deref(),
+                   Some("/home/user/.local/share"));
+        // Empty variables count as unset
+        assert_eq!(resolve_xdg_dir(Some(String::new()), home(), ".cache").as_deref(),
+                   Some("/home/user/.cache"));
+        assert_eq!(resolve_xdg_dir(None, None, ".cache"), None);
+    }
+
+    #[test]
+    fn source_urls() {
+        let settings = Settings::layer(&[], &[("source_url", "https://example.com/{lang}/{lang}.json")]).unwrap();
+
+        assert_eq!(settings.source_url("Polish"), "https://example.com/Polish/Polish.json");
+    }
+
+    #[test]
+    fn display_reads_back_as_toml() {
+        let settings = Settings::layer(&[], &[("user", "inflectived"), ("port", "8004")]).unwrap();
+        let shown = settings.to_string();
+
+        assert!(shown.lines().next().unwrap().starts_with("# /etc/inflectived/config.toml"));
+
+        let dir = TempDir::new();
+        let file = config_file(&dir, "config.toml", &shown);
+        let read = Settings::layer(&[file], &[]).unwrap();
+
+        assert_eq!(read.port, 8004);
+        assert_eq!(read.user.as_deref(), Some("inflectived"));
+        assert_eq!(read.data_dir, settings.data_dir);
+    }
+}
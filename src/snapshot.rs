@@ -0,0 +1,474 @@
+//! Archives of installed languages, so a language can be built once and
+//! installed on other servers without downloading and parsing its dump again
+//!
+//! An archive is a gzipped tarball of `manifest.json` followed by the
+//! language's database.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::{WordDb, DbError};
+use crate::entry::WiktionaryEntry;
+use crate::language::Language;
+use crate::settings::Settings;
+use crate::util;
+use crate::version::Version;
+
+/// Version of the archive layout, bumped when it changes
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+/// Describes the language in an archive
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub format: u32,
+    /// Version of the daemon that built the database
+    pub version: Version,
+    pub code: String,
+    pub name: String,
+    /// Unix time the language was upgraded at
+    pub upgraded: i64,
+    /// Unix time the dump was downloaded at, if it was still cached
+    pub downloaded: Option<i64>,
+    pub entries: usize,
+    /// How many of the entries are generated "form-of" entries
+    pub generated: usize,
+    /// SHA-256 of the database
+    pub sha256: String,
+}
+
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The file isn't an archive of a language
+    Invalid(String),
+    /// The archive was made by a newer daemon
+    Unsupported(Version),
+    /// The database doesn't match its checksum
+    Corrupted,
+    Db(DbError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Invalid(reason) => write!(f, "Not a language archive: {}", reason),
+            SnapshotError::Unsupported(version) => write!(f, "The archive was made by inflectived {}, which is newer than this one ({})",
+                                                          version, Version::current()),
+            SnapshotError::Corrupted => write!(f, "The database in the archive doesn't match its checksum"),
+            SnapshotError::Db(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => SnapshotError::Db(DbError::AccessDenied),
+            _ => SnapshotError::Io(e)
+        }
+    }
+}
+
+impl From<DbError> for SnapshotError {
+    fn from(e: DbError) -> Self {
+        SnapshotError::Db(e)
+    }
+}
+
+impl From<rusqlite::Error> for SnapshotError {
+    fn from(e: rusqlite::Error) -> Self {
+        SnapshotError::Db(DbError::from(e))
+    }
+}
+
+/// Hashes everything written through it
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where to keep the database while packing or unpacking it, as it can be
+/// as big as the dump
+fn scratch_path(settings: &Settings, code: &str) -> String {
+    format!("{}/{}-{}.snapshot.db", settings.cache_dir, code, process::id())
+}
+
+/// Write an archive of an installed language
+pub fn pack<W: Write>(db: &WordDb,
+                      settings: &Settings,
+                      lang: &Language,
+                      writer: W) -> Result<Manifest, SnapshotError> {
+    util::try_create_dir(&settings.cache_dir)?;
+
+    let path = scratch_path(settings, &lang.code);
+    fs::remove_file(&path).ok();
+
+    let result = pack_snapshot(db, lang, &path, writer);
+    fs::remove_file(&path).ok();
+
+    result
+}
+
+fn pack_snapshot<W: Write>(db: &WordDb,
+                           lang: &Language,
+                           path: &str,
+                           writer: W) -> Result<Manifest, SnapshotError> {
+    // A copy rather than the database itself, which may change or have
+    // uncheckpointed pages in its write-ahead log
+    db.snapshot_lang(lang, path)?;
+
+    let (entries, generated) = count_entries(path)?;
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    let downloaded = fs::metadata(db.cache_file(lang))
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .map(|modified| modified.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
+
+    let manifest = Manifest {
+        format: FORMAT,
+        version: lang.version.clone().unwrap_or_else(Version::current),
+        code: lang.code.clone(),
+        name: lang.name.clone(),
+        upgraded: lang.upgraded.unwrap_or(0),
+        downloaded,
+        entries,
+        generated,
+        sha256: format!("{:x}", hasher.finalize()),
+    };
+
+    let mut archive = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+    let manifest_json = serde_json::to_vec_pretty(&manifest).unwrap();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.upgraded as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, MANIFEST, manifest_json.as_slice())?;
+
+    archive.append_path_with_name(path, format!("{}.db", lang.code))?;
+
+    archive.into_inner()?.finish()?.flush()?;
+
+    Ok(manifest)
+}
+
+/// How many entries a copy of a language's database has, and how many of
+/// them are generated
+///
+/// Reads every column restoring it copies, so a database that isn't a
+/// language's fails here.
+fn count_entries(path: &str) -> rusqlite::Result<(usize, usize)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    conn.query_row("SELECT count(id), count(name) FROM types", [], |_| Ok(()))?;
+
+    let mut statement = conn.prepare("SELECT id, word, type_id, content FROM words")?;
+    let mut rows = statement.query([])?;
+
+    let mut entries = 0;
+    let mut generated = 0;
+
+    while let Some(row) = rows.next()? {
+        let content: String = row.get(3)?;
+
+        entries += 1;
+        if WiktionaryEntry::is_generated(&content) {
+            generated += 1;
+        }
+    }
+
+    Ok((entries, generated))
+}
+
+/// Install the language in an archive, replacing it if already installed
+pub fn unpack<R: Read>(db: &WordDb, settings: &Settings, reader: R) -> Result<Manifest, SnapshotError> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut files = archive.entries()?;
+
+    let mut file = match files.next() {
+        Some(file) => file?,
+        None => return Err(SnapshotError::Invalid(String::from("it's empty")))
+    };
+
+    if file.path()?.to_str() != Some(MANIFEST) {
+        return Err(SnapshotError::Invalid(format!("{} doesn't come first", MANIFEST)));
+    }
+
+    let mut manifest_json = String::new();
+    file.read_to_string(&mut manifest_json)?;
+
+    let manifest: Manifest = match serde_json::from_str(&manifest_json) {
+        Ok(manifest) => manifest,
+        Err(e) => return Err(SnapshotError::Invalid(format!("bad {}: {}", MANIFEST, e)))
+    };
+
+    if manifest.format > FORMAT || manifest.version > Version::current() {
+        return Err(SnapshotError::Unsupported(manifest.version));
+    }
+
+    if !Language::valid_code(&manifest.code) {
+        return Err(SnapshotError::Invalid(format!("bad language code \"{}\"", manifest.code)));
+    }
+
+    // Names end up in the paths of cached dumps
+    if manifest.name.is_empty() || manifest.name.contains('/') {
+        return Err(SnapshotError::Invalid(format!("bad language name \"{}\"", manifest.name)));
+    }
+
+    let mut file = match files.next() {
+        Some(file) => file?,
+        None => return Err(SnapshotError::Invalid(String::from("the database is missing")))
+    };
+
+    let db_name = format!("{}.db", manifest.code);
+    if file.path()?.to_str() != Some(db_name.as_str()) {
+        return Err(SnapshotError::Invalid(format!("expected {} after {}", db_name, MANIFEST)));
+    }
+
+    util::try_create_dir(&settings.cache_dir)?;
+
+    let path = scratch_path(settings, &manifest.code);
+    let result = unpack_snapshot(db, &manifest, &mut file, &path);
+    fs::remove_file(&path).ok();
+
+    result.map(|_| manifest)
+}
+
+fn unpack_snapshot<R: Read>(db: &WordDb,
+                            manifest: &Manifest,
+                            file: &mut R,
+                            path: &str) -> Result<(), SnapshotError> {
+    let mut writer = HashWriter {
+        inner: File::create(path)?,
+        hasher: Sha256::new(),
+    };
+
+    io::copy(file, &mut writer)?;
+    writer.flush()?;
+
+    if format!("{:x}", writer.hasher.finalize()) != manifest.sha256 {
+        return Err(SnapshotError::Corrupted);
+    }
+
+    // The checksum only tells the database arrived as it was packed
+    let (entries, _) = count_entries(path).map_err(|e| {
+        SnapshotError::Invalid(format!("the database isn't a language's: {}", e))
+    })?;
+
+    if entries != manifest.entries {
+        return Err(SnapshotError::Invalid(format!("the database has {} entries, {} says {}",
+                                                  entries, MANIFEST, manifest.entries)));
+    }
+
+    db.restore_lang(&Language::new(&manifest.code, &manifest.name),
+                    path,
+                    &manifest.version,
+                    manifest.upgraded,
+                    manifest.entries)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    /// The manifest and database in an archive
+    fn read_archive(archive: &[u8]) -> (Manifest, Vec<u8>) {
+        let mut archive = tar::Archive::new(GzDecoder::new(archive));
+        let mut files = archive.entries().unwrap();
+
+        let manifest = serde_json::from_reader(files.next().unwrap().unwrap()).unwrap();
+        let mut database = Vec::new();
+        files.next().unwrap().unwrap().read_to_end(&mut database).unwrap();
+
+        (manifest, database)
+    }
+
+    fn write_archive(manifest: &Manifest, database: &[u8]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+        for (name, data) in [(String::from(MANIFEST), serde_json::to_vec(manifest).unwrap()),
+                             (format!("{}.db", manifest.code), database.to_vec())] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, name, data.as_slice()).unwrap();
+        }
+
+        archive.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    async fn packed(settings: &Settings, db: &WordDb) -> Vec<u8> {
+        let lang = testing::install(settings, db, "pol").await;
+        let mut archive = Vec::new();
+
+        pack(db, settings, &lang, &mut archive).ok().unwrap();
+
+        archive
+    }
+
+    #[tokio::test]
+    async fn languages_survive_a_round_trip() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let archive = packed(&settings, &db).await;
+        let lang = db.get_installed_lang("pol").unwrap();
+
+        let (manifest, _) = read_archive(&archive);
+        assert_eq!(manifest.code, "pol");
+        assert_eq!(manifest.upgraded, lang.upgraded.unwrap());
+        // kot, pies and być, and the forms generated from their tables
+        assert_eq!(manifest.entries, 6);
+        assert_eq!(manifest.generated, 3);
+
+        // Into another server
+        let other_dir = TempDir::new();
+        let other_settings = testing::settings(&other_dir);
+        let other = WordDb::new(&other_settings, "inflectived.db");
+
+        let manifest = unpack(&other, &other_settings, archive.as_slice()).ok().unwrap();
+        let restored = other.get_installed_lang("pol").unwrap();
+
+        assert_eq!(manifest.entries, 6);
+        assert_eq!(restored.version, lang.version);
+        assert_eq!(restored.upgraded, lang.upgraded);
+        assert_eq!(other.get_entries(&restored, "kot").unwrap(), db.get_entries(&lang, "kot").unwrap());
+    }
+
+    #[tokio::test]
+    async fn corrupted_databases_are_rejected() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let (manifest, mut database) = read_archive(&packed(&settings, &db).await);
+
+        let last = database.len() - 1;
+        database[last] ^= 1;
+
+        let result = unpack(&db, &settings, write_archive(&manifest, &database).as_slice());
+
+        assert!(matches!(result, Err(SnapshotError::Corrupted)));
+    }
+
+    #[tokio::test]
+    async fn databases_must_match_their_manifest() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let (mut manifest, database) = read_archive(&packed(&settings, &db).await);
+
+        manifest.entries += 1;
+
+        let result = unpack(&db, &settings, write_archive(&manifest, &database).as_slice());
+
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+        // The installed language is left alone
+        assert_eq!(db.get_installed_lang("pol").unwrap().upgraded, Some(manifest.upgraded));
+    }
+
+    #[tokio::test]
+    async fn other_databases_are_invalid() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let (mut manifest, _) = read_archive(&packed(&settings, &db).await);
+
+        let path = format!("{}/other.db", dir.0);
+        Connection::open(&path).unwrap().execute("CREATE TABLE words (word TEXT)", []).unwrap();
+        let database = fs::read(&path).unwrap();
+        manifest.sha256 = sha256(&database);
+
+        let result = unpack(&db, &settings, write_archive(&manifest, &database).as_slice());
+
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn codes_must_be_safe_in_file_names() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let (mut manifest, database) = read_archive(&packed(&settings, &db).await);
+
+        manifest.code = String::from("langs/pol");
+
+        let result = unpack(&db, &settings, write_archive(&manifest, &database).as_slice());
+
+        assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn names_must_be_safe_in_file_names() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let (mut manifest, database) = read_archive(&packed(&settings, &db).await);
+
+        for name in ["", "../Polish"] {
+            manifest.name = String::from(name);
+
+            let result = unpack(&db, &settings, write_archive(&manifest, &database).as_slice());
+
+            assert!(matches!(result, Err(SnapshotError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn newer_archives_are_unsupported() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        let manifest = Manifest {
+            format: FORMAT + 1,
+            version: Version::current(),
+            code: String::from("pol"),
+            name: String::from("Polish"),
+            upgraded: 0,
+            downloaded: None,
+            entries: 0,
+            generated: 0,
+            sha256: sha256(b""),
+        };
+
+        let result = unpack(&db, &settings, write_archive(&manifest, b"").as_slice());
+
+        assert!(matches!(result, Err(SnapshotError::Unsupported(_))));
+    }
+}
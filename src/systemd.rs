@@ -0,0 +1,387 @@
+//! Integration with systemd: readiness notifications and socket activation
+//!
+//! Both are no-ops when the daemon isn't run by systemd.
+
+use std::convert::Infallible;
+use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::net::{self, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::net as unix;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Server, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use rocket::{Build, Rocket, Shutdown};
+use rocket::data::Limits;
+use rocket::http::{Header, Method};
+use rocket::local::asynchronous::Client;
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+
+// First file descriptor passed by systemd, after stdin, stdout and stderr
+const LISTEN_FDS_START: i32 = 3;
+
+/// Tell systemd about a state change, e.g. `READY=1`
+pub fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        notify_at(&path, state);
+    }
+}
+
+fn notify_at(path: &OsStr, state: &str) {
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Couldn't notify systemd: {}", e);
+            return;
+        }
+    };
+
+    // Names starting with @ are in the abstract namespace
+    #[cfg(target_os = "linux")]
+    let result = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => unix::SocketAddr::from_abstract_name(name)
+                                       .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+        None => socket.send_to(state.as_bytes(), path)
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let result = socket.send_to(state.as_bytes(), path);
+
+    if let Err(e) = result {
+        log::warn!("Couldn't notify systemd: {}", e);
+    }
+}
+
+/// The listening socket systemd passed, if the daemon was socket activated
+pub fn listener() -> Option<net::TcpListener> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+
+    // Meant for another process, e.g. the one that spawned this one
+    if pid != process::id() || fds < 1 {
+        return None;
+    }
+
+    if fds > 1 {
+        log::warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    // systemd hands over ownership of the descriptor
+    let listener = unsafe { net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.set_nonblocking(true).ok()?;
+
+    Some(listener)
+}
+
+/// Serve `rocket` on the listening socket systemd passed, until shutdown
+///
+/// Rocket 0.5 only serves on sockets it binds itself, so connections are
+/// accepted here and their requests dispatched through a local client,
+/// along with the client's address. Rocket doesn't run shutdown fairings
+/// for local clients, which is left to the caller.
+pub async fn serve(rocket: Rocket<Build>, listener: net::TcpListener) -> Result<Arc<Client>, Box<dyn Error + Send + Sync>> {
+    // Untracked, or cookies would be shared between clients
+    let client = Arc::new(Client::untracked(rocket).await?);
+
+    let shutdown = client.rocket().shutdown();
+    let grace = Duration::from_secs(client.rocket().config().shutdown.grace as u64);
+    let body_limit = body_limit(&client.rocket().config().limits);
+
+    tokio::spawn(on_signal(shutdown.clone()));
+
+    let service_client = client.clone();
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let client = service_client.clone();
+        let remote = conn.remote_addr();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| dispatch(client.clone(), remote, body_limit, req)))
+        }
+    });
+
+    let server = Server::from_tcp(listener)?.tcp_nodelay(true)
+                                            .serve(make_service)
+                                            .with_graceful_shutdown(shutdown.clone());
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result?,
+        _ = shutdown => {
+            // In-flight requests get the grace period to finish
+            if tokio::time::timeout(grace, server).await.is_err() {
+                log::warn!("Requests still running after the grace period, stopping anyway");
+            }
+        }
+    }
+
+    Ok(client)
+}
+
+/// Shut down on SIGINT or SIGTERM, like Rocket does for servers of its own
+async fn on_signal(shutdown: Shutdown) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::error!("Couldn't listen for SIGTERM: {}", e);
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::warn!("Received SIGINT, shutting down"),
+        _ = terminate.recv() => log::warn!("Received SIGTERM, shutting down")
+    }
+
+    shutdown.notify();
+}
+
+/// The largest body any of Rocket's data guards would read, so bodies
+/// aren't held in memory beyond what Rocket itself allows
+fn body_limit(limits: &Limits) -> u64 {
+    [
+        ("form", Limits::FORM),
+        ("data-form", Limits::DATA_FORM),
+        ("file", Limits::FILE),
+        ("string", Limits::STRING),
+        ("bytes", Limits::BYTES),
+        ("json", Limits::JSON),
+        ("msgpack", Limits::MESSAGE_PACK),
+    ].into_iter()
+     .map(|(name, default)| limits.get(name).unwrap_or(default).as_u64())
+     .max()
+     .unwrap()
+}
+
+/// Read a request's body, or `None` if it's longer than `limit`
+async fn read_body(mut body: Body, limit: u64) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut read = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if (read.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+
+        read.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(read))
+}
+
+/// Dispatch a request to Rocket, streaming the response back
+async fn dispatch(client: Arc<Client>,
+                  remote: SocketAddr,
+                  body_limit: u64,
+                  req: hyper::Request<Body>) -> Result<hyper::Response<Body>, hyper::Error> {
+    let (parts, body) = req.into_parts();
+
+    // Rejected before reading anything, when the client says how long it is
+    let length = parts.headers.get(header::CONTENT_LENGTH)
+                              .and_then(|length| length.to_str().ok())
+                              .and_then(|length| length.parse::<u64>().ok());
+
+    if length.is_some_and(|length| length > body_limit) {
+        return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    let Some(body) = read_body(body, body_limit).await? else {
+        return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+    };
+
+    let method = match Method::from_str(parts.method.as_str()) {
+        Ok(method) => method,
+        Err(_) => return Ok(status_response(StatusCode::NOT_IMPLEMENTED))
+    };
+
+    let uri = parts.uri.path_and_query().map_or("/", |uri| uri.as_str()).to_owned();
+
+    let (head_sender, head) = oneshot::channel();
+
+    // The response borrows the client, so it's read where it's dispatched
+    tokio::spawn(async move {
+        let mut request = client.req(method, uri).remote(remote).body(body);
+
+        for (name, value) in &parts.headers {
+            request.add_header(Header::new(name.as_str().to_owned(),
+                                           String::from_utf8_lossy(value.as_bytes()).into_owned()));
+        }
+
+        let mut response = request.dispatch().await;
+
+        let (mut sender, body) = Body::channel();
+        let mut head = hyper::Response::new(body);
+
+        *head.status_mut() = StatusCode::from_u16(response.status().code)
+                                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        for header in response.headers().iter() {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(header.name().as_str().as_bytes()),
+                                            HeaderValue::from_str(header.value())) {
+                head.headers_mut().append(name, value);
+            }
+        }
+
+        if head_sender.send(head).is_err() {
+            return;
+        }
+
+        let mut buffer = vec![0; 8192];
+
+        loop {
+            match response.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    // The client hung up
+                    if sender.send_data(Bytes::copy_from_slice(&buffer[..read])).await.is_err() {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    log::warn!("Couldn't read a response: {}", e);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(head.await.unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+fn status_response(status: StatusCode) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = status;
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use rocket::{get, routes};
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[get("/peer")]
+    fn peer(addr: SocketAddr) -> String {
+        addr.to_string()
+    }
+
+    #[get("/stop")]
+    fn stop(shutdown: Shutdown) {
+        shutdown.notify();
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> (SocketAddr, String) {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        (stream.local_addr().unwrap(), response)
+    }
+
+    #[rocket::async_test]
+    async fn requests_keep_the_client_address() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let rocket = rocket::build().mount("/", routes![peer, stop]);
+        let server = tokio::spawn(serve(rocket, listener));
+
+        let (local, response) = tokio::task::spawn_blocking(move || get(addr, "/peer")).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(&local.to_string()), "{}", response);
+
+        let (_, response) = tokio::task::spawn_blocking(move || get(addr, "/missing")).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+
+        tokio::task::spawn_blocking(move || get(addr, "/stop")).await.unwrap();
+
+        let client = tokio::time::timeout(Duration::from_secs(10), server).await.unwrap().unwrap().unwrap();
+
+        assert_eq!(client.rocket().routes().count(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn long_bodies_are_refused() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let limits = ["form", "data-form", "file", "string", "bytes", "json", "msgpack"].into_iter()
+                                                                                       .fold(Limits::new(), |limits, name| limits.limit(name, 16.into()));
+        let figment = rocket::Config::figment().merge(("limits", limits));
+        let rocket = rocket::custom(figment).mount("/", routes![peer, stop]);
+        let server = tokio::spawn(serve(rocket, listener));
+
+        let post = move |length: usize, declared: bool| {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            let body = "x".repeat(length);
+
+            if declared {
+                write!(stream, "POST /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                       length, body).unwrap();
+            } else {
+                write!(stream, "POST /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                       length, body).unwrap();
+            }
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).ok();
+
+            response
+        };
+
+        let response = tokio::task::spawn_blocking(move || post(1024, true)).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        let response = tokio::task::spawn_blocking(move || post(1024, false)).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        // Small enough to get to Rocket, which has no POST route there
+        let response = tokio::task::spawn_blocking(move || post(8, true)).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        tokio::task::spawn_blocking(move || get(addr, "/stop")).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[test]
+    fn notifications_are_sent() {
+        let dir = TempDir::new();
+        let path = format!("{}/notify", dir.0);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify_at(OsStr::new(&path), "READY=1");
+
+        let mut buffer = [0; 16];
+        let read = socket.recv(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..read], b"READY=1");
+    }
+}
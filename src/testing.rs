@@ -0,0 +1,139 @@
+//! Helpers shared by tests
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rocket::{Build, Rocket, catchers};
+
+use crate::auth::{self, VerifiedPassword};
+use crate::cache::CacheControl;
+use crate::database::{WordDb, NoProgress};
+use crate::language::Language;
+use crate::jobs::Jobs;
+use crate::keys::{self, ApiKeys};
+use crate::metrics::{Metrics, RequestTimer};
+use crate::settings::Settings;
+use crate::version::Version;
+
+/// A small dump of Polish, with declension tables to generate entries from
+pub const DUMP: &str = r#"{"word": "kot", "pos": "noun", "senses": [{"glosses": ["cat"]}], "forms": [{"form": "kota", "tags": ["genitive", "singular"], "source": "Declension"}, {"form": "koty", "tags": ["nominative", "plural"], "source": "Declension"}]}
+{"word": "pies", "pos": "noun", "senses": [{"glosses": ["dog"]}]}
+{"word": "być", "pos": "verb", "senses": [{"glosses": ["to be"]}], "forms": [{"form": "jest", "tags": ["third-person", "singular"], "source": "Conjugation"}]}
+"#;
+
+/// A single entry of Polish
+pub const KOT: &str = r#"{"word": "kot", "pos": "noun", "senses": [{"glosses": ["cat"]}]}"#;
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system's temporary one, removed when dropped
+pub struct TempDir(pub String);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = format!("{}/inflectived-test-{}-{}",
+                           env::temp_dir().display(),
+                           process::id(),
+                           NEXT.fetch_add(1, Ordering::Relaxed));
+
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Settings keeping everything under `dir`, with dumps only ever read from
+/// the cache
+pub fn settings(dir: &TempDir) -> Settings {
+    Settings {
+        data_dir: format!("{}/data", dir.0),
+        cache_dir: format!("{}/cache", dir.0),
+        frontend_dir: format!("{}/frontend", dir.0),
+        address: String::from("127.0.0.1"),
+        port: 0,
+        source_url: format!("file://{}/missing/{{lang}}.json", dir.0),
+        index_url: format!("{}/index.html", dir.0),
+        user: None,
+        log_level: String::from("off"),
+        log_format: String::from("human"),
+        redact_words: false,
+        shutdown_grace: 0,
+        rebuild_outdated: false,
+    }
+}
+
+/// Put a dump in the cache, so upgrading the language doesn't download it
+pub fn cache_dump(settings: &Settings, name: &str, dump: &str) {
+    fs::create_dir_all(&settings.cache_dir).unwrap();
+    fs::write(format!("{}/{}.json", settings.cache_dir, name), dump).unwrap();
+}
+
+/// Install a language from `DUMP`
+pub async fn install(settings: &Settings, db: &WordDb, code: &str) -> Language {
+    let lang = db.get_lang(code).unwrap();
+
+    cache_dump(settings, &lang.name, DUMP);
+    db.upgrade_lang(&lang, &NoProgress).await.ok().unwrap();
+    db.reload_langs();
+
+    db.get_installed_lang(code).unwrap()
+}
+
+/// Install Polish the way releases before per-language files did, with
+/// its tables and `kot` in the catalog itself
+pub fn legacy_catalog(db: &WordDb) {
+    let Version(major, minor, patch) = Version::current();
+    let catalog = db.connect().unwrap();
+
+    catalog.execute_batch("
+    CREATE TABLE langs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        code TINYTEXT UNIQUE NOT NULL,
+        name TINYTEXT NOT NULL,
+        major INTEGER NOT NULL,
+        minor INTEGER NOT NULL,
+        patch INTEGER NOT NULL
+    );
+    CREATE TABLE pol_types (id INTEGER PRIMARY KEY, name TINYTEXT UNIQUE NOT NULL);
+    CREATE TABLE pol_words (id INTEGER PRIMARY KEY, word TINYTEXT NOT NULL, type_id INTEGER NOT NULL, content MEDIUMTEXT NOT NULL);
+    INSERT INTO pol_types (name) VALUES ('noun');
+    ").unwrap();
+    catalog.execute("INSERT INTO pol_words (word, type_id, content) VALUES ('kot', 1, ?)", [KOT]).unwrap();
+    catalog.execute("INSERT INTO langs (code, name, major, minor, patch) VALUES ('pol', 'Polish', ?, ?, ?)",
+                    [major, minor, patch]).unwrap();
+}
+
+/// Authorization header for the admin password `rocket` sets, admin:hunter2
+pub const ADMIN: &str = "Basic YWRtaW46aHVudGVyMg==";
+
+/// The daemon's routes and state, as `run` sets them up, with the admin
+/// password set to hunter2
+pub fn rocket(settings: &Settings, db: &WordDb, require_api_key: bool) -> Rocket<Build> {
+    db.set_admin_password_hash(&auth::hash_password("hunter2")).ok().unwrap();
+
+    let mut app = rocket::build().manage(db.clone())
+                                 .manage(settings.clone())
+                                 .manage(CacheControl(String::from("max-age=60")))
+                                 .manage(Jobs::default())
+                                 .manage(VerifiedPassword::default())
+                                 .manage(ApiKeys::new(require_api_key))
+                                 .manage(Metrics::default())
+                                 .attach(RequestTimer)
+                                 .register("/", catchers![keys::too_many_requests])
+                                 .register("/admin", catchers![auth::unauthorized]);
+
+    for (base, routes) in crate::mounts() {
+        app = app.mount(base, routes);
+    }
+
+    app
+}
@@ -1,12 +1,227 @@
 use std::fs;
-use std::io::ErrorKind;
-
-pub fn try_create_dir(dir: &str) {
-    match fs::create_dir(dir) {
-        Err(e) => match e.kind() {
-            ErrorKind::AlreadyExists => {},
-            _ => panic!("{}", e)
-        },
-        _ => {}
+use std::io::{self, Write, ErrorKind};
+use std::os::unix::fs::{lchown, MetadataExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use std::ffi::CString;
+
+use nix::unistd::{self, User};
+use rocket::Request;
+use rocket::response::{self, Responder, Response};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+pub fn try_create_dir(dir: &str) -> io::Result<()> {
+    match fs::create_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(())
+    }
+}
+
+/// Sends everything written to it through a channel, for streaming the
+/// output of blocking code from an async context
+///
+/// Errors can be sent through the same channel, for `ChannelReader` to fail
+/// with.
+pub struct ChannelWriter(pub Sender<io::Result<Vec<u8>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.blocking_send(Ok(buf.to_vec())) {
+            Ok(()) => Ok(buf.len()),
+            // The receiving end hung up, e.g. an HTTP client disconnected
+            Err(_) => Err(io::Error::from(ErrorKind::BrokenPipe))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads what a `ChannelWriter` sends, failing with the first error sent
+///
+/// As a responder, it streams the response body.
+pub struct ChannelReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl ChannelReader {
+    /// Wait for the first chunk, so that failing before anything was sent
+    /// can still be told apart from sending nothing
+    pub async fn start(mut receiver: Receiver<io::Result<Vec<u8>>>) -> io::Result<Self> {
+        let chunk = match receiver.recv().await {
+            Some(chunk) => chunk?,
+            None => Vec::new()
+        };
+
+        Ok(Self { receiver, chunk, read: 0 })
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context<'_>,
+                 buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.read == self.chunk.len() {
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                },
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // Nothing read means the end
+                None => return Poll::Ready(Ok(()))
+            }
+        }
+
+        let end = self.chunk.len().min(self.read + buf.remaining());
+        buf.put_slice(&self.chunk[self.read..end]);
+        self.read = end;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ChannelReader {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build().streamed_body(self).ok()
+    }
+}
+
+/// Give what root owns under `path` to `uid` and `gid`, without following
+/// symlinks. Nothing happens if `path` doesn't exist
+fn chown_root_owned(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    if metadata.uid() == 0 {
+        lchown(path, Some(uid), Some(gid))?;
+    }
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_root_owned(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Switch the whole process to an unprivileged user, along with its groups
+///
+/// What root created under `owned` until then, e.g. the databases opened at
+/// startup, is handed to the user first, so it can still be written to.
+pub fn drop_privileges(user: &str, owned: &[&str]) -> Result<(), String> {
+    let user = match User::from_name(user) {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(format!("No such user: {}", user)),
+        Err(e) => return Err(e.to_string())
+    };
+
+    for path in owned {
+        chown_root_owned(Path::new(path), user.uid.as_raw(), user.gid.as_raw())
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    // The groups have to go first, as changing them needs root
+    let name = CString::new(user.name.as_str()).unwrap();
+    unistd::initgroups(&name, user.gid).map_err(|e| e.to_string())?;
+    unistd::setgid(user.gid).map_err(|e| e.to_string())?;
+    unistd::setuid(user.uid).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn root_owned_files_are_handed_over() {
+        // Only root can give files away
+        if !unistd::geteuid().is_root() {
+            return;
+        }
+
+        let dir = crate::testing::TempDir::new();
+        let langs = format!("{}/langs", dir.0);
+        let mine = format!("{}/mine.db", dir.0);
+
+        try_create_dir(&langs).unwrap();
+        fs::write(format!("{}/pol.db", langs), b"").unwrap();
+        fs::write(&mine, b"").unwrap();
+        lchown(&mine, Some(1234), Some(1234)).unwrap();
+
+        chown_root_owned(Path::new(&dir.0), 4321, 4321).unwrap();
+
+        for path in [dir.0.clone(), langs.clone(), format!("{}/pol.db", langs)] {
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (4321, 4321), "{}", path);
+        }
+
+        // Already given away
+        assert_eq!(fs::metadata(&mine).unwrap().uid(), 1234);
+
+        assert!(chown_root_owned(Path::new(&format!("{}/missing", dir.0)), 4321, 4321).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reads_chunks_in_order() {
+        let (sender, receiver) = mpsc::channel(4);
+
+        sender.send(Ok(b"{\"word\": \"kot\"}\n".to_vec())).await.unwrap();
+        sender.send(Ok(Vec::new())).await.unwrap();
+        sender.send(Ok(b"{\"word\": \"pies\"}\n".to_vec())).await.unwrap();
+        drop(sender);
+
+        let mut body = String::new();
+        ChannelReader::start(receiver).await.unwrap().read_to_string(&mut body).await.unwrap();
+
+        assert_eq!(body, "{\"word\": \"kot\"}\n{\"word\": \"pies\"}\n");
+    }
+
+    #[tokio::test]
+    async fn fails_before_the_first_chunk() {
+        let (sender, receiver) = mpsc::channel(4);
+
+        sender.send(Err(io::Error::other("corrupted"))).await.unwrap();
+
+        assert!(ChannelReader::start(receiver).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_after_some_chunks() {
+        let (sender, receiver) = mpsc::channel(4);
+
+        sender.send(Ok(b"{}\n".to_vec())).await.unwrap();
+        sender.send(Err(io::Error::other("corrupted"))).await.unwrap();
+
+        let mut reader = ChannelReader::start(receiver).await.unwrap();
+        let mut body = Vec::new();
+
+        assert!(reader.read_to_end(&mut body).await.is_err());
+        assert_eq!(body, b"{}\n");
+    }
+
+    #[tokio::test]
+    async fn empty_without_chunks() {
+        let (sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>(4);
+        drop(sender);
+
+        let mut body = Vec::new();
+        ChannelReader::start(receiver).await.unwrap().read_to_end(&mut body).await.unwrap();
+
+        assert!(body.is_empty());
     }
 }
@@ -0,0 +1,398 @@
+//! Version 1 of the API, mounted at `/v1`
+//!
+//! Unlike the routes at the root, which pass entries through as they were
+//! found in the kaikki.org dumps, responses here are built from typed structs
+//! and won't change shape when the dumps do.
+
+use std::time::Instant;
+
+use rocket::{get, FromForm};
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde::Serialize;
+use utoipa::{ToSchema, IntoParams};
+
+use crate::database::WordDb;
+use crate::language;
+use crate::iso639::Codes;
+use crate::version::Version;
+use crate::entry;
+use crate::cache::Cached;
+use crate::lookup::Lookup;
+use crate::views::LangsParams;
+
+/// An entry of a word
+#[derive(Serialize, Debug, ToSchema)]
+#[schema(as = v1::Entry)]
+pub struct Entry {
+    /// The word, as found in the dictionary
+    pub headword: String,
+    /// Part of speech, e.g. "noun" or "verb"
+    pub pos: String,
+    pub senses: Vec<Sense>,
+    pub forms: Vec<Form>,
+    pub sounds: Vec<Sound>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[schema(as = v1::Sense)]
+pub struct Sense {
+    pub glosses: Vec<String>,
+    pub tags: Vec<String>,
+    /// Words this sense is an inflected form of
+    pub form_of: Vec<String>,
+}
+
+/// An inflected form of the headword
+#[derive(Serialize, Debug, ToSchema)]
+#[schema(as = v1::Form)]
+pub struct Form {
+    pub form: String,
+    pub tags: Vec<String>,
+    /// Table the form was taken from, e.g. "Declension"
+    pub source: Option<String>,
+}
+
+/// A pronunciation of the headword
+#[derive(Serialize, Debug, ToSchema)]
+#[schema(as = v1::Sound)]
+pub struct Sound {
+    pub ipa: String,
+    pub tags: Vec<String>,
+}
+
+/// A language, installed or not
+#[derive(Serialize, Debug, ToSchema)]
+#[schema(as = v1::Language)]
+pub struct Language {
+    /// Code the language is installed under
+    pub code: String,
+    /// English name
+    pub name: String,
+    /// Every standard code of the language, any of which it can be asked
+    /// for by
+    pub codes: Codes,
+    /// Version of the daemon that built the language, if installed
+    pub version: Option<Version>,
+    /// Unix time the language was last upgraded at, if installed
+    pub upgraded: Option<i64>,
+    /// Whether an older daemon built the language, so it should be upgraded
+    /// again
+    pub outdated: bool,
+}
+
+impl From<&language::Language> for Language {
+    fn from(lang: &language::Language) -> Self {
+        Self {
+            code: lang.code.clone(),
+            name: lang.name.clone(),
+            codes: lang.codes.clone(),
+            version: lang.version.clone(),
+            upgraded: lang.upgraded,
+            outdated: lang.outdated,
+        }
+    }
+}
+
+impl From<entry::Entry> for Entry {
+    fn from(entry: entry::Entry) -> Self {
+        Self {
+            headword: entry.word,
+            pos: entry.pos,
+            senses: entry.senses.unwrap_or_default()
+                                .into_iter()
+                                .map(Sense::from)
+                                .collect(),
+            forms: entry.forms.unwrap_or_default()
+                              .into_iter()
+                              .map(Form::from)
+                              .collect(),
+            // Sounds without IPA are mostly audio files and rhymes, which are left out
+            sounds: entry.sounds.unwrap_or_default()
+                                .into_iter()
+                                .filter_map(|sound| Some(Sound {
+                                    ipa: sound.ipa?,
+                                    tags: sound.tags.unwrap_or_default()
+                                }))
+                                .collect(),
+        }
+    }
+}
+
+impl From<entry::Sense> for Sense {
+    fn from(sense: entry::Sense) -> Self {
+        Self {
+            glosses: sense.glosses.unwrap_or_default(),
+            tags: sense.tags.unwrap_or_default(),
+            form_of: sense.form_of.unwrap_or_default()
+                                  .into_iter()
+                                  .map(|form_of| form_of.word)
+                                  .collect(),
+        }
+    }
+}
+
+impl From<entry::Form> for Form {
+    fn from(form: entry::Form) -> Self {
+        Self {
+            form: form.form,
+            tags: form.tags.unwrap_or_default(),
+            source: form.source,
+        }
+    }
+}
+
+/// Decode entries as found in the dump, along with why the ones that don't
+/// fit the schema were left out
+fn decode(entries: &[String]) -> (Vec<Entry>, Vec<serde_json::Error>) {
+    let mut decoded = Vec::new();
+    let mut errors = Vec::new();
+
+    for json in entries {
+        match serde_json::from_str::<entry::Entry>(json) {
+            Ok(entry) => decoded.push(Entry::from(entry)),
+            Err(e) => errors.push(e)
+        }
+    }
+
+    (decoded, errors)
+}
+
+/// Get all entries of a word
+#[utoipa::path(
+    tag = "v1",
+    context_path = "/v1",
+    params(
+        ("lang", description = "Language code"),
+        ("word", description = "Word to look up"),
+    ),
+    responses(
+        (status = 200, description = "Entries of the word, possibly none", body = [v1::Entry]),
+        (status = 304, description = "The language was not upgraded since the given validators"),
+        (status = 401, description = "API keys are required and none or a wrong one was given"),
+        (status = 404, description = "The language is not installed"),
+        (status = 429, description = "The API key's rate limit or daily quota was exceeded"),
+        (status = 500, description = "The language's database couldn't be read")
+    ),
+    security((), ("api_key" = []))
+)]
+#[get("/langs/<lang>/words/<word>")]
+pub fn get_entries(lookup: Lookup, lang: &str, word: &str) -> Option<Cached<Result<Json<Vec<Entry>>, Status>>> {
+    let lang = lookup.installed_lang(lang);
+    lookup.log.word(word);
+    let lang = lang?;
+
+    Some(lookup.cached(&lang, || {
+        let entries = lookup.db.get_entries(&lang, word).map_err(|e| lookup.failed(&lang, e))?;
+
+        lookup.metrics.lookup(&lang.code, !entries.is_empty());
+        lookup.log.results(entries.len());
+
+        // Rather than failing the whole lookup, skip entries that don't fit
+        // the schema
+        let (entries, errors) = decode(&entries);
+
+        for e in errors {
+            log::warn!(lang = lang.code.as_str(); "Skipped an entry that doesn't fit the v1 schema: {}", e);
+            lookup.metrics.skipped_entry(&lang.code);
+        }
+
+        Ok(Json(entries))
+    }))
+}
+
+/// Query string of word searches
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LikeParams {
+    /// String the words must contain
+    like: String,
+    /// Maximum number of words to return (default 20)
+    limit: Option<usize>,
+    /// Number of words to skip (default 0)
+    offset: Option<usize>,
+}
+
+/// Search for words containing a string, shortest first
+#[utoipa::path(
+    tag = "v1",
+    context_path = "/v1",
+    params(
+        ("lang", description = "Language code"),
+        LikeParams,
+    ),
+    responses(
+        (status = 200, description = "Matching words", body = [String]),
+        (status = 304, description = "The language was not upgraded since the given validators"),
+        (status = 401, description = "API keys are required and none or a wrong one was given"),
+        (status = 404, description = "The language is not installed"),
+        (status = 429, description = "The API key's rate limit or daily quota was exceeded"),
+        (status = 500, description = "The language's database couldn't be read")
+    ),
+    security((), ("api_key" = []))
+)]
+#[get("/langs/<lang>/words?<params..>")]
+pub fn get_words_like(lookup: Lookup,
+                      lang: &str,
+                      params: LikeParams) -> Option<Cached<Result<Json<Vec<String>>, Status>>> {
+    let lang = lookup.installed_lang(lang);
+    lookup.log.word(&params.like);
+    let lang = lang?;
+
+    Some(lookup.cached(&lang, || {
+        let started = Instant::now();
+        let words = lookup.db.get_words_like(&lang,
+                                             &params.like,
+                                             params.limit.unwrap_or(20),
+                                             params.offset.unwrap_or(0))
+                             .map_err(|e| lookup.failed(&lang, e))?;
+
+        lookup.metrics.autocomplete(&lang.code, started.elapsed());
+        lookup.log.results(words.len());
+
+        Ok(Json(words))
+    }))
+}
+
+/// List languages
+#[utoipa::path(
+    tag = "v1",
+    context_path = "/v1",
+    params(LangsParams),
+    responses(
+        (status = 200, description = "Languages, sorted by name", body = [v1::Language])
+    )
+)]
+#[get("/langs?<params..>")]
+pub fn get_langs(db: &State<WordDb>, params: LangsParams) -> Json<Vec<Language>> {
+    let langs = if params.installed.unwrap_or(false) {
+        db.installed_langs()
+    } else {
+        db.installable_langs()
+    };
+
+    Json(langs.iter().map(Language::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn entries_get_defaults_for_missing_fields() {
+        let (entries, errors) = decode(&[String::from(r#"{"word": "kot", "pos": "noun"}"#)]);
+
+        assert!(errors.is_empty());
+        assert_eq!(entries[0].headword, "kot");
+        assert_eq!(entries[0].pos, "noun");
+        assert!(entries[0].senses.is_empty());
+        assert!(entries[0].forms.is_empty());
+        assert!(entries[0].sounds.is_empty());
+    }
+
+    #[test]
+    fn entries_are_flattened() {
+        let json = r#"{
+            "word": "kota",
+            "pos": "noun",
+            "senses": [{"glosses": ["genitive of kot"], "form_of": [{"word": "kot"}]}],
+            "forms": [{"form": "koty", "tags": ["plural"], "source": "Declension"}],
+            "sounds": [{"ipa": "/ˈkɔ.ta/"}, {"audio": "kota.ogg"}],
+            "etymology_text": "Left out"
+        }"#;
+
+        let (entries, _) = decode(&[String::from(json)]);
+        let entry = &entries[0];
+
+        assert_eq!(entry.senses[0].glosses, ["genitive of kot"]);
+        assert_eq!(entry.senses[0].form_of, ["kot"]);
+        assert_eq!(entry.forms[0].form, "koty");
+        assert_eq!(entry.forms[0].source.as_deref(), Some("Declension"));
+        // Sounds without IPA are left out
+        assert_eq!(entry.sounds.len(), 1);
+        assert_eq!(entry.sounds[0].ipa, "/ˈkɔ.ta/");
+    }
+
+    #[test]
+    fn entries_that_dont_fit_are_skipped() {
+        let (entries, errors) = decode(&[String::from(r#"{"word": "kot", "pos": "noun"}"#),
+                                         String::from(r#"{"word": "kot"}"#),
+                                         String::from(r#"{"word": "kot", "pos": "noun", "senses": 1}"#)]);
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn languages_keep_their_shape() {
+        let mut lang = language::Language::new("pol", "Polish");
+        lang.version = Some(Version(0, 1, 0));
+        lang.upgraded = Some(1_600_000_000);
+
+        let json = serde_json::to_value(Language::from(&lang)).unwrap();
+
+        assert_eq!(json, serde_json::json!({
+            "code": "pol",
+            "name": "Polish",
+            "codes": {
+                "iso639_1": "pl",
+                "iso639_2b": "pol",
+                "iso639_2t": "pol",
+                "iso639_3": "pol"
+            },
+            "version": [0, 1, 0],
+            "upgraded": 1_600_000_000,
+            "outdated": false
+        }));
+    }
+
+    #[rocket::async_test]
+    async fn only_installed_languages_are_looked_up() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+
+        let client = Client::untracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        let response = client.get("/v1/langs/pl/words/kot").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        for code in ["xx", "..%2Finflectived", "pol.db"] {
+            let response = client.get(format!("/v1/langs/{}/words/kot", code)).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+
+            let response = client.get(format!("/v1/langs/{}/words?like=ko", code)).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound);
+        }
+
+        assert_eq!(db.lang_files(), ["pol"]);
+    }
+
+    #[rocket::async_test]
+    async fn missing_language_files_are_server_errors() {
+        let dir = TempDir::new();
+        let settings = testing::settings(&dir);
+        let db = WordDb::new(&settings, "inflectived.db");
+        testing::install(&settings, &db, "pol").await;
+        std::fs::remove_file(db.lang_path("pol")).unwrap();
+
+        // A fresh handle, without connections opened before the removal
+        let db = WordDb::new(&settings, "inflectived.db");
+        let client = Client::untracked(testing::rocket(&settings, &db, false)).await.unwrap();
+
+        let response = client.get("/v1/langs/pol/words/kot").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let response = client.get("/v1/langs/pol/words?like=ko").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let response = client.get("/langs/pol/export").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+}
@@ -1,10 +1,28 @@
 use std::cmp::{PartialEq, PartialOrd, Ordering};
+use std::fmt;
 
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
+use utoipa::ToSchema;
 
-#[derive(Serialize, Debug, Clone, PartialEq)]
+use crate::{MAJOR, MINOR, PATCH};
+
+/// Version of the daemon that built a language database, as `[major, minor, patch]`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
 pub struct Version(pub u32, pub u32, pub u32);
 
+impl Version {
+    /// Version of this daemon
+    pub fn current() -> Self {
+        Self(MAJOR as u32, MINOR as u32, PATCH as u32)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
 impl PartialOrd for Version {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         if self.0 > other.0 {
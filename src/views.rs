@@ -1,91 +1,272 @@
 use std::fs;
+use std::io::{self, BufWriter};
+use std::time::Instant;
 
-use rocket::get;
+use rocket::{get, FromForm};
 use rocket::State;
-use rocket::http::Status;
-use rocket::response::{content, status};
+use rocket::http::{ContentType, Status};
+use rocket::response::content;
 use rocket::serde::json::Json;
-use rusqlite::params;
+use utoipa::{OpenApi, Modify, IntoParams};
+use utoipa::openapi::security::{SecurityScheme, HttpAuthScheme, Http, ApiKey as ApiKeyScheme, ApiKeyValue};
 
 use crate::database::WordDb;
-use crate::language::Language;
-use crate::FRONTEND_DIR;
+use crate::language::{self, Language};
+use crate::iso639::Codes;
+use crate::version::Version;
+use crate::cache::Cached;
+use crate::lookup::Lookup;
+use crate::projection::Projection;
+use crate::util::{ChannelWriter, ChannelReader};
+use crate::v1;
+use crate::admin;
+use crate::jobs::{JobStatus, JobState};
+use crate::settings::Settings;
+use crate::metrics;
+use crate::health::{self, Readiness, LangHealth, LangState};
 
 #[get("/")]
-pub fn frontend() -> content::RawHtml<String> {
-    match fs::read_to_string(&format!("{}/{}", FRONTEND_DIR, "index.html")) {
+pub fn frontend(settings: &State<Settings>) -> content::RawHtml<String> {
+    match fs::read_to_string(format!("{}/{}", settings.frontend_dir, "index.html")) {
         Ok(file) => content::RawHtml(file),
         Err(_) => content::RawHtml(String::from("<h1>No web frontend installed.</h1>"))
     }
 }
 
-#[get("/langs/<lang>/words/<word>")]
-pub fn get_entries(db: &State<WordDb>, lang: &str, word: &str) -> status::Custom<content::RawJson<String>> {
-    let conn = db.connect();
+/// Query string of word lookups
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LookupParams {
+    /// Comma-separated dotted paths of the fields to keep, e.g.
+    /// `word,pos,senses.glosses`
+    fields: Option<String>,
+}
 
-    let mut statement = conn.prepare(&format!(
-        "SELECT content
-        FROM {}_words
-        WHERE word = ?",
-        lang)
-    ).unwrap();
+/// Get all entries of a word
+///
+/// Entries are passed through as they were found in the kaikki.org dump.
+#[utoipa::path(
+    tag = "langs",
+    params(
+        ("lang", description = "Language code"),
+        ("word", description = "Word to look up"),
+        LookupParams,
+    ),
+    responses(
+        (status = 200, description = "Entries of the word, possibly none", body = [Object]),
+        (status = 304, description = "The language was not upgraded since the given validators"),
+        (status = 401, description = "API keys are required and none or a wrong one was given"),
+        (status = 404, description = "The language is not installed"),
+        (status = 429, description = "The API key's rate limit or daily quota was exceeded"),
+        (status = 500, description = "The language's database couldn't be read")
+    ),
+    security((), ("api_key" = []))
+)]
+#[get("/langs/<lang>/words/<word>?<params..>")]
+pub fn get_entries(lookup: Lookup,
+                   lang: &str,
+                   word: &str,
+                   params: LookupParams) -> Option<Cached<Result<content::RawJson<String>, Status>>> {
+    let lang = lookup.installed_lang(lang);
+    lookup.log.word(word);
+    let lang = lang?;
 
-    let mut rows = statement.query([word]).unwrap();
+    Some(lookup.cached(&lang, || {
+        let mut entries = lookup.db.get_entries(&lang, word).map_err(|e| lookup.failed(&lang, e))?;
 
-    let mut words = String::new();
+        lookup.metrics.lookup(&lang.code, !entries.is_empty());
+        lookup.log.results(entries.len());
 
-    words.push('[');
-    while let Some(row) = rows.next().unwrap() {
-        let content: String = row.get(0).unwrap();
-        words.push_str(&content);
-        words.push(',');
-    }
-    // Remove last comma
-    if words.pop().unwrap() == '[' {
-        words.push('[');
-    }
-    words.push(']');
+        if let Some(fields) = &params.fields {
+            let projection = Projection::parse(fields);
+
+            for entry in entries.iter_mut() {
+                let json = serde_json::from_str(entry).unwrap();
+                *entry = projection.apply(json).to_string();
+            }
+        }
 
-    status::Custom(Status::Ok, content::RawJson(words))
+        Ok(content::RawJson(format!("[{}]", entries.join(","))))
+    }))
 }
 
-#[get("/langs/<lang>/words?<like>&<limit>&<offset>")]
-pub fn get_entries_like(db: &State<WordDb>, lang: &str, like: &str, limit: usize, offset: usize) -> Json<Vec<String>> {
-    let conn = db.connect();
-
-    let mut statement = conn.prepare(&format!(
-        "SELECT word
-        FROM {}_words
-        WHERE word LIKE ?
-        ORDER BY length(word) ASC
-        LIMIT ?
-        OFFSET ?",
-        lang)
-    ).unwrap();
-
-    let mut rows = statement.query(params![format!("%{}%", like), limit, offset]).unwrap();
-
-    let mut words = Vec::new();
-    while let Some(row) = rows.next().unwrap() {
-        words.push(row.get(0).unwrap());
-    }
+/// Query string of word searches
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LikeParams {
+    /// String the words must contain
+    like: String,
+    /// Maximum number of words to return
+    limit: usize,
+    /// Number of words to skip
+    offset: usize,
+}
+
+/// Search for words containing a string, shortest first
+#[utoipa::path(
+    tag = "langs",
+    params(
+        ("lang", description = "Language code"),
+        LikeParams,
+    ),
+    responses(
+        (status = 200, description = "Matching words", body = [String]),
+        (status = 304, description = "The language was not upgraded since the given validators"),
+        (status = 401, description = "API keys are required and none or a wrong one was given"),
+        (status = 404, description = "The language is not installed"),
+        (status = 429, description = "The API key's rate limit or daily quota was exceeded"),
+        (status = 500, description = "The language's database couldn't be read")
+    ),
+    security((), ("api_key" = []))
+)]
+#[get("/langs/<lang>/words?<params..>")]
+pub fn get_entries_like(lookup: Lookup,
+                        lang: &str,
+                        params: LikeParams) -> Option<Cached<Result<Json<Vec<String>>, Status>>> {
+    let lang = lookup.installed_lang(lang);
+    lookup.log.word(&params.like);
+    let lang = lang?;
 
-    Json(words)
+    Some(lookup.cached(&lang, || {
+        let started = Instant::now();
+        let words = lookup.db.get_words_like(&lang, &params.like, params.limit, params.offset)
+                             .map_err(|e| lookup.failed(&lang, e))?;
+
+        lookup.metrics.autocomplete(&lang.code, started.elapsed());
+        lookup.log.results(words.len());
+
+        Ok(Json(words))
+    }))
+}
+
+/// Query string of exports
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    /// Include automatically generated "form-of" entries
+    generated: Option<bool>,
 }
 
-#[get("/langs?<installed>")]
-pub fn get_langs(db: &State<WordDb>, installed: bool) -> Json<Vec<Language>> {
+/// Export all entries of a language as newline-delimited JSON
+///
+/// Should the export fail once entries were sent, the response is cut short
+/// and the failure logged, as the status can't be changed anymore.
+#[utoipa::path(
+    tag = "langs",
+    params(
+        ("lang", description = "Language code"),
+        ExportParams,
+    ),
+    responses(
+        (status = 200, description = "One entry per line", content_type = "application/x-ndjson", body = Object),
+        (status = 401, description = "API keys are required and none or a wrong one was given"),
+        (status = 404, description = "The language is not installed"),
+        (status = 429, description = "The API key's rate limit or daily quota was exceeded"),
+        (status = 500, description = "The language's database couldn't be read")
+    ),
+    security((), ("api_key" = []))
+)]
+#[get("/langs/<lang>/export?<params..>")]
+pub async fn export(lookup: Lookup<'_>,
+                    lang: &str,
+                    params: ExportParams) -> Option<Result<(ContentType, ChannelReader), Status>> {
+    let lang = lookup.installed_lang(lang)?;
+    let conn = match lookup.db.read_lang(&lang) {
+        Ok(conn) => conn,
+        Err(e) => return Some(Err(lookup.failed(&lang, e)))
+    };
+
+    // Only a few chunks are buffered, so whole languages are never held in
+    // memory
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(64 * 1024, ChannelWriter(sender.clone()));
+
+        if let Err(e) = WordDb::export_entries(&conn, params.generated.unwrap_or(false), writer) {
+            // The client hung up, so there's nobody left to tell
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                log::error!(lang = lang.code.as_str(); "Export failed: {}", e);
+                sender.blocking_send(Err(e)).ok();
+            }
+        }
+    });
+
+    Some(match ChannelReader::start(receiver).await {
+        Ok(reader) => Ok((ContentType::new("application", "x-ndjson"), reader)),
+        Err(_) => Err(Status::InternalServerError)
+    })
+}
+
+/// Query string of language listings
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LangsParams {
+    /// List only installed languages
+    pub installed: Option<bool>,
+}
+
+/// List languages
+#[utoipa::path(
+    tag = "langs",
+    params(LangsParams),
+    responses(
+        (status = 200, description = "Languages, sorted by name", body = [Language])
+    )
+)]
+#[get("/langs?<params..>")]
+pub fn get_langs(db: &State<WordDb>, params: LangsParams) -> Json<Vec<Language>> {
     let mut langs: Vec<Language> = Vec::new();
 
-    if installed {
-        for lang in &db.installed_langs {
+    if params.installed.unwrap_or(false) {
+        for lang in db.installed_langs().iter() {
             langs.push(lang.clone())
         }
     } else {
-        for lang in &db.installable_langs {
+        for lang in db.installable_langs().iter() {
             langs.push(lang.clone())
         }
     }
 
     Json(langs)
 }
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(get_entries, get_entries_like, export, get_langs, openapi,
+          v1::get_entries, v1::get_words_like, v1::get_langs,
+          admin::upgrade_lang, admin::remove_lang, admin::reload_langs, admin::get_jobs, admin::get_job,
+          admin::get_job_events,
+          metrics::get_metrics, health::healthz, health::readyz),
+    components(schemas(Language, language::Status, Codes, Version,
+                       v1::Entry, v1::Sense, v1::Form, v1::Sound, v1::Language,
+                       JobStatus, JobState,
+                       Readiness, LangHealth, LangState)),
+    modifiers(&Security)
+)]
+pub struct ApiDoc;
+
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("admin",
+                                           SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)));
+            components.add_security_scheme("api_key",
+                                           SecurityScheme::ApiKey(ApiKeyScheme::Header(ApiKeyValue::new("X-API-Key"))));
+        }
+    }
+}
+
+/// Get this description of the API
+#[utoipa::path(
+    tag = "meta",
+    responses(
+        (status = 200, description = "OpenAPI description of the API", body = Object)
+    )
+)]
+#[get("/openapi.json")]
+pub fn openapi() -> content::RawJson<String> {
+    content::RawJson(ApiDoc::openapi().to_pretty_json().unwrap())
+}